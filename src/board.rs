@@ -1,6 +1,29 @@
+//! The pieces, positions and actions of the game, with a short text notation for each
+//!
+//! A piece is written with one letter per attribute: `B`lack or `W`hite, `S`hort or `T`all,
+//! s`Q`uare or `R`ound and `H`ollow or `F`illed (for example `WTRF`). Positions are written like in
+//! chess, from `a1` to `d4`: the letter is the column and the digit is the row. An action is the
+//! position where the reserve piece is placed followed by the piece given to the opponent, as in
+//! `c3 WTRF`.
+
 use crate::traits;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// The error returned when parsing a piece, position, action or rules from text fails
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseError(pub(crate) String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid notation: {}", self.0)
+    }
+}
+
+impl Error for ParseError {}
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct Piece {
     pub hollow: bool,
     pub square: bool,
@@ -11,7 +34,7 @@ pub struct Piece {
 impl From<u8> for Piece {
     fn from(v: u8) -> Self {
         Self {
-            hollow: ((v >> 0) & 1) != 0,
+            hollow: (v & 1) != 0,
             square: ((v >> 1) & 1) != 0,
             short: ((v >> 2) & 1) != 0,
             black: ((v >> 3) & 1) != 0,
@@ -21,14 +44,116 @@ impl From<u8> for Piece {
 
 impl From<Piece> for u8 {
     fn from(p: Piece) -> Self {
-        ((p.hollow as u8) << 0)
-            + ((p.square as u8) << 1)
-            + ((p.short as u8) << 2)
-            + ((p.black as u8) << 3)
+        (p.hollow as u8) + ((p.square as u8) << 1) + ((p.short as u8) << 2) + ((p.black as u8) << 3)
+    }
+}
+
+impl fmt::Display for Piece {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}{}",
+            if self.black { 'B' } else { 'W' },
+            if self.short { 'S' } else { 'T' },
+            if self.square { 'Q' } else { 'R' },
+            if self.hollow { 'H' } else { 'F' },
+        )
+    }
+}
+
+/// Parse the four attribute letters, in any order and case
+impl FromStr for Piece {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let error = || ParseError(s.to_owned());
+        let mut attributes = [None; 4];
+        for c in s.chars() {
+            let (attribute, value) = match c.to_ascii_uppercase() {
+                'B' => (0, true),
+                'W' => (0, false),
+                'S' => (1, true),
+                'T' => (1, false),
+                'Q' => (2, true),
+                'R' => (2, false),
+                'H' => (3, true),
+                'F' => (3, false),
+                _ => return Err(error()),
+            };
+            if attributes[attribute].replace(value).is_some() {
+                return Err(error());
+            }
+        }
+        match attributes {
+            [Some(black), Some(short), Some(square), Some(hollow)] => Ok(Piece {
+                hollow,
+                square,
+                short,
+                black,
+            }),
+            _ => Err(error()),
+        }
+    }
+}
+
+/// One of the two values of an attribute of the pieces
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub enum Trait {
+    Black,
+    White,
+    Short,
+    Tall,
+    Square,
+    Round,
+    Hollow,
+    Filled,
+}
+
+impl Piece {
+    /// The values of the four attributes, in the order of the notation
+    pub fn traits(&self) -> [Trait; 4] {
+        [
+            if self.black {
+                Trait::Black
+            } else {
+                Trait::White
+            },
+            if self.short {
+                Trait::Short
+            } else {
+                Trait::Tall
+            },
+            if self.square {
+                Trait::Square
+            } else {
+                Trait::Round
+            },
+            if self.hollow {
+                Trait::Hollow
+            } else {
+                Trait::Filled
+            },
+        ]
+    }
+}
+
+impl fmt::Display for Trait {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Trait::Black => "black",
+            Trait::White => "white",
+            Trait::Short => "short",
+            Trait::Tall => "tall",
+            Trait::Square => "square",
+            Trait::Round => "round",
+            Trait::Hollow => "hollow",
+            Trait::Filled => "filled",
+        };
+        write!(f, "{}", name)
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct Position {
     pub row: u8,
     pub col: u8,
@@ -49,7 +174,29 @@ impl From<Position> for u8 {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", (b'a' + self.col) as char, self.row + 1)
+    }
+}
+
+impl FromStr for Position {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        match s.as_bytes() {
+            [col @ b'a'..=b'd', row @ b'1'..=b'4'] | [col @ b'A'..=b'D', row @ b'1'..=b'4'] => {
+                Ok(Position {
+                    row: row - b'1',
+                    col: col.to_ascii_lowercase() - b'a',
+                })
+            }
+            _ => Err(ParseError(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct Action {
     pub position: Position,
     pub piece: Piece,
@@ -70,4 +217,41 @@ impl From<Action> for u8 {
     }
 }
 
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.position, self.piece)
+    }
+}
+
+impl FromStr for Action {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words[..] {
+            [position, piece] => Ok(Action {
+                position: position.parse()?,
+                piece: piece.parse()?,
+            }),
+            _ => Err(ParseError(s.to_owned())),
+        }
+    }
+}
+
 impl traits::Action for Action {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_round_trip_through_text() {
+        for v in 0..=255 {
+            let action = Action::from(v);
+            assert_eq!(action.to_string().parse::<Action>().unwrap(), action);
+        }
+        assert!("c3".parse::<Action>().is_err());
+        assert!("e1 WTRF".parse::<Action>().is_err());
+        assert!("c3 WTRB".parse::<Action>().is_err());
+    }
+}
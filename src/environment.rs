@@ -1,113 +1,801 @@
 use crate::board::*;
-use crate::traits;
+use crate::persist::StateKind;
+use crate::random::{self, SeededRng};
+use crate::record::{GameRecord, GameResult};
+use crate::rules::Rules;
+use crate::symmetry::Symmetry;
+use crate::traits::{self, Environment as _};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+/// A packed representation of the board: the piece at each cell is stored in the corresponding
+/// nibble of `cells` and `occupied` has the bits of the non-empty cells set.
+/// The rules are part of the state, so that it can be simulated and compared to symmetric states
+/// without knowing the environment
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, Serialize, Deserialize)]
 pub struct State {
-    board: [[Option<Piece>; 4]; 4],
-    reserve: Piece,
+    cells: u64,
+    occupied: u16,
+    reserve: u8,
+    rules: Rules,
+}
+
+impl StateKind for State {
+    const NAME: &'static str = "quarto";
 }
 
 impl State {
-    fn new() -> Self {
+    pub(crate) fn new(rules: Rules) -> Self {
         State {
-            board: [[None; 4]; 4],
-            reserve: Piece::from(15),
+            cells: 0,
+            occupied: 0,
+            reserve: 15,
+            rules,
+        }
+    }
+
+    pub fn rules(&self) -> Rules {
+        self.rules
+    }
+
+    /// Return the piece at the given position, if any
+    pub fn piece(&self, position: Position) -> Option<Piece> {
+        let cell = u8::from(position);
+        if self.occupied & (1 << cell) == 0 {
+            None
+        } else {
+            Some(Piece::from(self.cell(cell)))
         }
     }
+
+    /// Return the piece that must be placed in this turn
+    pub fn reserve(&self) -> Piece {
+        Piece::from(self.reserve)
+    }
+
+    /// Bit mask of the empty cells
+    pub(crate) fn empty_cells(&self) -> u16 {
+        !self.occupied
+    }
+
+    /// Bit mask of the pieces on the board
+    pub(crate) fn placed_pieces(&self) -> u16 {
+        bits(self.occupied).fold(0, |mask, cell| mask | (1 << self.cell(cell)))
+    }
+
+    /// Bit mask of the pieces on the board and in the reserve
+    pub(crate) fn used_pieces(&self) -> u16 {
+        self.placed_pieces() | (1 << self.reserve)
+    }
+
+    /// Check whether there is a Quarto anywhere on the board
+    pub(crate) fn has_quarto(&self) -> bool {
+        bits(self.occupied).any(|cell| self.has_quarto_at(cell))
+    }
+
+    fn cell(&self, cell: u8) -> u8 {
+        ((self.cells >> (4 * cell)) & 0xF) as u8
+    }
+
+    fn place(&mut self, cell: u8, piece: u8) {
+        self.cells |= (piece as u64) << (4 * cell);
+        self.occupied |= 1 << cell;
+    }
+
+    /// Check whether any line crossing the given cell is a Quarto
+    fn has_quarto_at(&self, cell: u8) -> bool {
+        self.rules
+            .lines()
+            .iter()
+            .any(|&line| line & (1 << cell) != 0 && self.has_common_trait(line))
+    }
+
+    /// Check whether placing the piece at the given empty cell would win the game
+    pub(crate) fn wins_with(&self, cell: u8, piece: u8) -> bool {
+        let mut state = *self;
+        state.place(cell, piece);
+        state.has_quarto_at(cell)
+    }
+
+    /// Return a copy of this state with the reserve piece placed at the given cell and the given
+    /// piece as the new reserve
+    pub(crate) fn after(&self, cell: u8, piece: u8) -> Self {
+        let mut state = *self;
+        state.place(cell, self.reserve);
+        state.reserve = piece;
+        state
+    }
+
+    /// Return a copy of this state with another reserve piece
+    pub(crate) fn with_reserve(&self, piece: u8) -> Self {
+        State {
+            reserve: piece,
+            ..*self
+        }
+    }
+
+    /// Check whether all four pieces in the given line share at least one attribute
+    fn has_common_trait(&self, line: u16) -> bool {
+        if self.occupied & line != line {
+            return false;
+        }
+
+        let (mut all, mut any) = (0xF, 0);
+        for cell in bits(line) {
+            let piece = self.cell(cell);
+            all &= piece;
+            any |= piece;
+        }
+        all != 0 || any != 0xF
+    }
+
+    /// The Quartos on the board, only keeping the ones that cross the given cell if there is one
+    pub(crate) fn quartos(&self, cell: Option<u8>) -> Vec<Quarto> {
+        self.rules
+            .lines()
+            .iter()
+            .filter(|&&line| cell.is_none_or(|cell| line & (1 << cell) != 0))
+            .filter(|&&line| self.has_common_trait(line))
+            .map(|&line| {
+                let (mut all, mut any) = (0xF, 0);
+                for cell in bits(line) {
+                    let piece = self.cell(cell);
+                    all &= piece;
+                    any |= piece;
+                }
+                // Attributes set in all pieces, then attributes set in none of them
+                let (set, unset) = (Piece::from(all).traits(), Piece::from(any).traits());
+                let traits = [8, 4, 2, 1]
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, &bit)| {
+                        if all & bit != 0 {
+                            Some(set[i])
+                        } else if any & bit == 0 {
+                            Some(unset[i])
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                Quarto {
+                    kind: LineKind::of(line),
+                    positions: bits(line).map(Position::from).collect(),
+                    traits,
+                }
+            })
+            .collect()
+    }
+
+    /// The outcome of a finished game, where the Quartos crossing `last_cell` (or all of them)
+    /// give the win to the first player or to the second one
+    pub(crate) fn outcome(&self, last_cell: Option<u8>, first_player_wins: bool) -> Outcome {
+        let quartos = self.quartos(last_cell);
+        let result = if quartos.is_empty() {
+            GameResult::Draw
+        } else if first_player_wins {
+            GameResult::FirstPlayerWins
+        } else {
+            GameResult::SecondPlayerWins
+        };
+        Outcome { result, quartos }
+    }
+
+    /// Apply a symmetry to the whole board and to the reserve piece
+    fn transformed(&self, symmetry: &Symmetry) -> Self {
+        let mut state = State {
+            cells: 0,
+            occupied: 0,
+            reserve: symmetry.apply_piece_u8(self.reserve),
+            rules: self.rules,
+        };
+        for cell in bits(self.occupied) {
+            state.place(
+                symmetry.apply_cell(cell),
+                symmetry.apply_piece_u8(self.cell(cell)),
+            );
+        }
+        state
+    }
+
+    /// A key that totally orders states sharing the same reserve piece
+    fn key(&self) -> u128 {
+        ((self.occupied as u128) << 64) | self.cells as u128
+    }
 }
 
+/// A position is written as the four rows of the board, from the fourth to the first, separated by
+/// `/`, followed by the reserve piece. Each cell is either a piece or `.` when empty, for example
+/// `WTRF.../..../BSQFWTQH../.... BTRH`.
+/// The pieces that can still be given are the ones neither on the board nor in the reserve
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in (0..4).rev() {
+            for col in 0..4 {
+                match self.piece(Position { row, col }) {
+                    Some(piece) => write!(f, "{}", piece)?,
+                    None => write!(f, ".")?,
+                }
+            }
+            if row > 0 {
+                write!(f, "/")?;
+            }
+        }
+        write!(f, " {}", self.reserve())
+    }
+}
+
+/// Parse a position with the standard rules, checking that it can be reached in a game that is not
+/// over yet
+impl FromStr for State {
+    type Err = PositionError;
+
+    fn from_str(s: &str) -> Result<Self, PositionError> {
+        State::parse(s, Rules::standard())
+    }
+}
+
+impl State {
+    /// Parse a position played with the given rules, like `from_str()`
+    pub fn parse(s: &str, rules: Rules) -> Result<Self, PositionError> {
+        let notation_error = || PositionError::Notation(s.to_owned());
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (board, reserve) = match words[..] {
+            [board, reserve] => (board, reserve),
+            _ => return Err(notation_error()),
+        };
+
+        let reserve: Piece = reserve.parse().map_err(|_| notation_error())?;
+        let mut state = State {
+            cells: 0,
+            occupied: 0,
+            reserve: u8::from(reserve),
+            rules,
+        };
+        let mut used_pieces: u16 = 1 << state.reserve;
+        let rows: Vec<&str> = board.split('/').collect();
+        if rows.len() != 4 {
+            return Err(notation_error());
+        }
+        for (row, text) in (0..4).rev().zip(rows) {
+            let mut col = 0;
+            let mut rest = text;
+            while !rest.is_empty() {
+                if col == 4 {
+                    return Err(notation_error());
+                }
+                if let Some(after) = rest.strip_prefix('.') {
+                    rest = after;
+                } else {
+                    let piece: Piece = rest
+                        .get(..4)
+                        .and_then(|piece| piece.parse().ok())
+                        .ok_or_else(notation_error)?;
+                    let piece = u8::from(piece);
+                    if used_pieces & (1 << piece) != 0 {
+                        return Err(PositionError::DuplicatePiece(Piece::from(piece)));
+                    }
+                    used_pieces |= 1 << piece;
+                    state.place(u8::from(Position { row, col }), piece);
+                    rest = &rest[4..];
+                }
+                col += 1;
+            }
+            if col != 4 {
+                return Err(notation_error());
+            }
+        }
+
+        // Once the last piece is given, the environment places it right away
+        if state.occupied.count_ones() >= 15 || state.has_quarto() {
+            return Err(PositionError::GameOver);
+        }
+        Ok(state)
+    }
+}
+
+/// How the first piece to place is picked
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Opening {
+    /// Always the same piece (`BSQH` by default)
+    Fixed(Piece),
+    /// A random piece at every reset
+    Random,
+    /// The first player gives it to the second one, like in the real game. Giving a piece is a
+    /// separate decision, so this is only supported by `split::SplitEnvironment`
+    Choice,
+}
+
+impl Default for Opening {
+    fn default() -> Self {
+        Opening::Fixed(Piece::from(15))
+    }
+}
+
+/// Written as the piece, `random` or `choice`
+impl fmt::Display for Opening {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Opening::Fixed(piece) => write!(f, "{}", piece),
+            Opening::Random => write!(f, "random"),
+            Opening::Choice => write!(f, "choice"),
+        }
+    }
+}
+
+impl FromStr for Opening {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        match s {
+            "random" => Ok(Opening::Random),
+            "choice" => Ok(Opening::Choice),
+            _ => s.parse().map(Opening::Fixed),
+        }
+    }
+}
+
+impl TryFrom<String> for Opening {
+    type Error = ParseError;
+
+    fn try_from(s: String) -> Result<Self, ParseError> {
+        s.parse()
+    }
+}
+
+impl From<Opening> for String {
+    fn from(opening: Opening) -> Self {
+        opening.to_string()
+    }
+}
+
+/// An environment that can be created with any rules and opening, so that a saved training run
+/// can be resumed in the same game
+pub trait Variant: traits::Environment + Sized {
+    fn variant(rules: Rules, opening: Opening) -> Self;
+}
+
+impl Opening {
+    /// Pick the first piece to place, or `None` if it is chosen by the first player
+    pub(crate) fn piece(&self, rng: &mut Option<SeededRng>) -> Option<u8> {
+        match self {
+            Opening::Fixed(piece) => Some(u8::from(*piece)),
+            Opening::Random => Some(
+                rng.get_or_insert_with(random::from_entropy)
+                    .gen_range(0, 16),
+            ),
+            Opening::Choice => None,
+        }
+    }
+}
+
+/// The kind of line made by four cells
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub enum LineKind {
+    Row,
+    Column,
+    Diagonal,
+    /// A 2x2 square, in the advanced variant
+    Square,
+}
+
+impl LineKind {
+    fn of(line: u16) -> Self {
+        if (0..4).any(|row| line == 0x000F << (4 * row)) {
+            LineKind::Row
+        } else if (0..4).any(|col| line == 0x1111 << col) {
+            LineKind::Column
+        } else if line == 0x8421 || line == 0x1248 {
+            LineKind::Diagonal
+        } else {
+            LineKind::Square
+        }
+    }
+}
+
+impl fmt::Display for LineKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LineKind::Row => "row",
+            LineKind::Column => "column",
+            LineKind::Diagonal => "diagonal",
+            LineKind::Square => "square",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Four pieces in a line that share at least one attribute
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Quarto {
+    pub kind: LineKind,
+    /// The four positions of the line, from `a1` to `d4`
+    pub positions: Vec<Position>,
+    /// The attributes shared by the four pieces
+    pub traits: Vec<Trait>,
+}
+
+/// Written as the positions followed by the shared attributes, like `a1 b2 c3 d4 (tall, round)`
+impl fmt::Display for Quarto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for position in &self.positions {
+            write!(f, "{} ", position)?;
+        }
+        let traits: Vec<String> = self.traits.iter().map(|t| t.to_string()).collect();
+        write!(f, "({})", traits.join(", "))
+    }
+}
+
+/// How a game ended
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Outcome {
+    pub result: GameResult,
+    /// The Quartos made by the winning piece: there can be several of them, and none for a draw
+    pub quartos: Vec<Quarto>,
+}
+
+/// The reason a position could not be set up
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum PositionError {
+    /// The text is not a valid position
+    Notation(String),
+    /// The same piece was found twice on the board or in the reserve
+    DuplicatePiece(Piece),
+    /// The game is already over in this position
+    GameOver,
+    /// The action at the given index of the sequence is not valid
+    IllegalAction {
+        index: usize,
+        action: Action,
+        error: StepError,
+    },
+    /// A tag of a game record cannot be used to set up the game
+    InvalidTag { name: String, value: String },
+    /// The game record has fewer actions than asked for
+    MissingActions { ply: usize, actions: usize },
+}
+
+impl fmt::Display for PositionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PositionError::Notation(text) => write!(f, "invalid position: {}", text),
+            PositionError::DuplicatePiece(piece) => write!(f, "{} is used twice", piece),
+            PositionError::GameOver => write!(f, "the game is already over"),
+            PositionError::IllegalAction {
+                index,
+                action,
+                error,
+            } => write!(
+                f,
+                "action {} ({}) is not valid: {}",
+                index + 1,
+                action,
+                error
+            ),
+            PositionError::InvalidTag { name, value } => {
+                write!(f, "invalid {} tag: {:?}", name, value)
+            }
+            PositionError::MissingActions { ply, actions } => {
+                write!(
+                    f,
+                    "asked for {} actions, but the record has {}",
+                    ply, actions
+                )
+            }
+        }
+    }
+}
+
+impl Error for PositionError {}
+
+/// The reason an action cannot be applied to the environment
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StepError {
+    /// There is already a piece at this position
+    Occupied(Position),
+    /// The piece is already on the board
+    PieceUsed(Piece),
+    /// The piece cannot be given, because it is the one being placed
+    PieceNotAvailable(Piece),
+    /// The game is already over (or was not started with `reset()`)
+    GameOver,
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepError::Occupied(position) => write!(f, "{} is not empty", position),
+            StepError::PieceUsed(piece) => write!(f, "{} is already on the board", piece),
+            StepError::PieceNotAvailable(piece) => {
+                write!(f, "{} cannot be given, it is the piece being placed", piece)
+            }
+            StepError::GameOver => write!(f, "the game is already over"),
+        }
+    }
+}
+
+impl Error for StepError {}
+
 impl traits::State for State {
     fn game_depth(&self) -> u16 {
-        let mut depth = 0;
-        for row in &self.board {
-            for cell in row {
-                if cell.is_some() {
-                    depth += 1;
-                }
+        self.occupied.count_ones() as u16
+    }
+}
+
+impl traits::Canonical<Action> for State {
+    type Transform = Symmetry;
+
+    /// The canonical state is the one with the smallest key among all symmetric states whose
+    /// reserve is the piece `0`
+    fn canonical(&self) -> (Self, Symmetry) {
+        let mut best = None;
+        for symmetry in Symmetry::normalizing(self.reserve(), self.rules) {
+            let state = self.transformed(&symmetry);
+            let key = state.key();
+            match &best {
+                Some((best_key, _, _)) if *best_key <= key => {}
+                _ => best = Some((key, state, symmetry)),
             }
         }
-        depth
+        let (_, state, symmetry) = best.unwrap();
+        (state, symmetry)
     }
 }
 
+#[derive(Clone)]
 pub struct Environment {
     state: State,
-    available_positions: Vec<Position>,
-    available_pieces: Vec<Piece>,
+    /// Bit mask of the empty cells
+    available_positions: u16,
+    /// Bit mask of the pieces that can still be given to the opponent
+    available_pieces: u16,
+    /// The actions applied since the environment was reset or created, used to undo them
+    history: Vec<Move>,
+    /// The valid actions, updated after every change and reused to avoid allocating on every step
+    actions: Vec<Action>,
+    opening: Opening,
+    /// Only used for random openings, created when first needed if not seeded
+    rng: Option<SeededRng>,
+}
+
+/// An applied action, with what is needed to restore the environment as it was before it
+#[derive(Copy, Clone)]
+struct Move {
+    action: Action,
+    state: State,
+    available_positions: u16,
+    available_pieces: u16,
 }
 
 impl Environment {
     pub fn new() -> Self {
+        Environment::with_rules(Rules::standard())
+    }
+
+    /// Create an environment for the given rules. The calling rule is not supported, since claiming
+    /// a Quarto cannot be part of an action that also gives a piece
+    pub fn with_rules(rules: Rules) -> Self {
+        assert!(!rules.calling, "the calling rule needs split turns");
         Environment {
-            state: State::new(),
-            available_positions: Vec::new(),
-            available_pieces: Vec::new(),
+            state: State::new(rules),
+            available_positions: 0,
+            available_pieces: 0,
+            history: Vec::new(),
+            actions: Vec::new(),
+            opening: Opening::default(),
+            rng: None,
         }
     }
 
+    /// Set how the first piece is picked on `reset()`. Openings chosen by the players are not
+    /// supported, since a fused action cannot give a piece without placing one
+    pub fn opening(mut self, opening: Opening) -> Self {
+        assert_ne!(
+            opening,
+            Opening::Choice,
+            "choosing the opening needs split turns"
+        );
+        self.opening = opening;
+        self
+    }
+
+    /// Create an environment in the given state. The available positions and pieces are derived
+    /// from the board and the reserve piece
+    pub fn from_state(state: State) -> Self {
+        assert!(!state.rules.calling, "the calling rule needs split turns");
+        let mut env = Environment {
+            state,
+            available_positions: state.empty_cells(),
+            available_pieces: !state.used_pieces(),
+            history: Vec::new(),
+            actions: Vec::new(),
+            opening: Opening::default(),
+            rng: None,
+        };
+        env.update_actions();
+        env
+    }
+
+    /// Create an environment from a position written as described in `State`'s `Display`
+    pub fn from_position(position: &str, rules: Rules) -> Result<Self, PositionError> {
+        Ok(Environment::from_state(State::parse(position, rules)?))
+    }
+
+    /// Create an environment by playing the given actions from the start of the game. The last
+    /// action may end the game, but no action can follow the end
+    pub fn from_actions(actions: &[Action], rules: Rules) -> Result<Self, PositionError> {
+        Environment::with_rules(rules).replay(actions)
+    }
+
+    /// Create an environment by playing the actions of a game record, or only the first `ply` of
+    /// them. The game is set up with the `Rules` and `Opening` tags of the record, if present, and
+    /// otherwise with the given rules and the default opening.
+    /// Like `from_actions()`, no action can follow the end of the game
+    pub fn from_record(
+        record: &GameRecord<Action>,
+        ply: Option<usize>,
+        rules: Rules,
+    ) -> Result<Self, PositionError> {
+        let invalid_tag = |name: &str, value: &str| PositionError::InvalidTag {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        };
+        let rules = match record.tag("Rules") {
+            None => rules,
+            // The calling rule needs split turns
+            Some(value) => match value.parse::<Rules>() {
+                Ok(rules) if !rules.calling => rules,
+                _ => return Err(invalid_tag("Rules", value)),
+            },
+        };
+        let opening = match record.tag("Opening") {
+            None => Opening::default(),
+            // The piece actually given is needed to replay the game
+            Some(value) => match value.parse() {
+                Ok(Opening::Fixed(piece)) => Opening::Fixed(piece),
+                _ => return Err(invalid_tag("Opening", value)),
+            },
+        };
+        let actions = match ply {
+            None => &record.actions[..],
+            Some(ply) => record
+                .actions
+                .get(..ply)
+                .ok_or(PositionError::MissingActions {
+                    ply,
+                    actions: record.actions.len(),
+                })?,
+        };
+        Environment::with_rules(rules)
+            .opening(opening)
+            .replay(actions)
+    }
+
+    /// Reset the environment and play the given actions
+    fn replay(mut self, actions: &[Action]) -> Result<Self, PositionError> {
+        self.reset();
+        for (index, &action) in actions.iter().enumerate() {
+            self.try_step(action)
+                .map_err(|error| PositionError::IllegalAction {
+                    index,
+                    action,
+                    error,
+                })?;
+        }
+        Ok(self)
+    }
+
+    /// Check whether the game is over, either won or with the board full
+    pub fn is_over(&self) -> bool {
+        self.available_positions == 0 || self.state.has_quarto()
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Bit mask of the empty cells. Together with `available_pieces()`, it describes the valid
+    /// actions without building them
+    pub fn available_positions(&self) -> u16 {
+        self.available_positions
+    }
+
+    /// Bit mask of the pieces that can still be given to the opponent
+    pub fn available_pieces(&self) -> u16 {
+        self.available_pieces
+    }
+
+    /// Describe how the game ended, or return `None` if it is not over
+    pub fn outcome(&self) -> Option<Outcome> {
+        if !self.is_over() {
+            return None;
+        }
+        // The game ends as soon as a Quarto is made, so it was made by the last piece placed. The
+        // first player places the odd pieces
+        let first_player_placed = self.state.occupied.count_ones() % 2 == 1;
+        Some(self.state.outcome(None, first_player_placed))
+    }
+
     /// Return valid actions for the current state
-    fn actions(&self) -> Vec<Action> {
-        let mut actions =
-            Vec::with_capacity(self.available_positions.len() * self.available_pieces.len());
-        for &position in &self.available_positions {
-            for &piece in &self.available_pieces {
-                actions.push(Action { piece, position });
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    /// Fill the list of valid actions, reusing its memory
+    fn update_actions(&mut self) {
+        self.actions.clear();
+        let mut pieces = [Piece::from(0); 16];
+        let mut num_pieces = 0;
+        for piece in bits(self.available_pieces) {
+            pieces[num_pieces] = Piece::from(piece);
+            num_pieces += 1;
+        }
+        for position in bits(self.available_positions) {
+            let position = Position::from(position);
+            for &piece in &pieces[..num_pieces] {
+                self.actions.push(Action { position, piece });
             }
         }
-        actions
     }
 
-    /// Return the final reward (if any), checking all lines that cross the given position
-    fn final_reward(&self, position: Position) -> Option<f32> {
-        fn pos(row: u8, col: u8) -> Position {
-            Position { row, col }
+    /// The actions applied since the environment was reset or created, from the first to the last
+    pub fn history(&self) -> impl Iterator<Item = Action> + '_ {
+        self.history.iter().map(|applied| applied.action)
+    }
+
+    /// Check whether the action can be applied
+    pub fn check(&self, action: Action) -> Result<(), StepError> {
+        let position = u8::from(action.position);
+        let piece = u8::from(action.piece);
+        if self.is_over() {
+            Err(StepError::GameOver)
+        } else if self.available_positions & (1 << position) == 0 {
+            Err(StepError::Occupied(action.position))
+        } else if self.available_pieces & (1 << piece) == 0 {
+            if piece == self.state.reserve {
+                Err(StepError::PieceNotAvailable(action.piece))
+            } else {
+                Err(StepError::PieceUsed(action.piece))
+            }
+        } else {
+            Ok(())
         }
+    }
 
-        let (r, c) = (position.row, position.col);
-        if self.has_common_trait(pos(r, 0), pos(r, 1), pos(r, 2), pos(r, 3))
-            || self.has_common_trait(pos(0, c), pos(1, c), pos(2, c), pos(3, c))
-            || self.has_common_trait(pos(0, 0), pos(1, 1), pos(2, 2), pos(3, 3))
-            || self.has_common_trait(pos(3, 0), pos(2, 1), pos(1, 2), pos(0, 3))
-        {
+    /// Like `step()`, but return an error instead of panicking if the action is not valid, for
+    /// actions that come from untrusted sources
+    pub fn try_step(&mut self, action: Action) -> Result<(State, f32, bool, &[Action]), StepError> {
+        self.check(action)?;
+        Ok(self.step(action))
+    }
+
+    /// Return the final reward (if any), checking all lines that cross the given position
+    fn final_reward(&self, position: u8) -> Option<f32> {
+        if self.state.has_quarto_at(position) {
             Some(100.)
-        } else if self.available_positions.is_empty() {
+        } else if self.available_positions == 0 {
             Some(0.)
         } else {
             None
         }
     }
 
-    fn has_common_trait(
-        &self,
-        pos1: Position,
-        pos2: Position,
-        pos3: Position,
-        pos4: Position,
-    ) -> bool {
-        match (
-            self.state.board[pos1.row as usize][pos1.col as usize],
-            self.state.board[pos2.row as usize][pos2.col as usize],
-            self.state.board[pos3.row as usize][pos3.col as usize],
-            self.state.board[pos4.row as usize][pos4.col as usize],
-        ) {
-            (Some(p1), Some(p2), Some(p3), Some(p4)) => {
-                macro_rules! is_same {
-                    ($field:ident) => {
-                        p1.$field == p2.$field && p1.$field == p3.$field && p1.$field == p4.$field
-                    };
-                }
-
-                is_same!(hollow) || is_same!(square) || is_same!(short) || is_same!(black)
-            }
-            _ => false,
-        }
+    /// Put the reserve piece at the given position
+    fn apply_position(&mut self, position: u8) {
+        assert_ne!(self.available_positions & (1 << position), 0);
+        self.available_positions &= !(1 << position);
+        self.state.place(position, self.state.reserve);
     }
+}
 
-    /// Put the reserve piece at the given position
-    fn apply_position(&mut self, position: Position) {
-        assert!(remove_item(&mut self.available_positions, &position));
-        let Position { row, col } = position;
-        self.state.board[row as usize][col as usize] = Some(self.state.reserve);
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -115,25 +803,42 @@ impl traits::Environment for Environment {
     type State = State;
     type Action = Action;
 
-    fn reset(&mut self) -> (State, Vec<Action>) {
-        self.state = State::new();
-        self.available_positions = (0..16).map(Position::from).collect();
-        self.available_pieces = (0..15).map(Piece::from).collect();
-        (self.state, self.actions())
+    fn reset(&mut self) -> (State, &[Action]) {
+        let reserve = self.opening.piece(&mut self.rng).unwrap();
+        self.state = State::new(self.state.rules).with_reserve(reserve);
+        self.history.clear();
+        self.available_positions = 0xFFFF;
+        self.available_pieces = !(1 << reserve);
+        self.update_actions();
+        (self.state, &self.actions)
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.rng = Some(random::seeded(seed));
     }
 
-    fn step(&mut self, action: Action) -> (State, f32, bool, Vec<Action>) {
+    fn step(&mut self, action: Action) -> (State, f32, bool, &[Action]) {
+        self.history.push(Move {
+            action,
+            state: self.state,
+            available_positions: self.available_positions,
+            available_pieces: self.available_pieces,
+        });
+
         // Apply move
-        self.apply_position(action.position);
-        assert!(remove_item(&mut self.available_pieces, &action.piece));
-        self.state.reserve = action.piece;
+        let position = u8::from(action.position);
+        let piece = u8::from(action.piece);
+        self.apply_position(position);
+        assert_ne!(self.available_pieces & (1 << piece), 0);
+        self.available_pieces &= !(1 << piece);
+        self.state.reserve = piece;
 
         // Check new state
-        let (reward, done) = match self.final_reward(action.position) {
+        let (reward, done) = match self.final_reward(position) {
             Some(reward) => (reward, true),
-            None if self.available_pieces.is_empty() => {
+            None if self.available_pieces == 0 => {
                 // Finalize the game if the last piece is to be chosen
-                let final_pos = self.available_positions[0];
+                let final_pos = self.available_positions.trailing_zeros() as u8;
                 self.apply_position(final_pos);
                 let reward = self.final_reward(final_pos).unwrap();
                 (-reward, true)
@@ -141,13 +846,165 @@ impl traits::Environment for Environment {
             None => (0., false),
         };
 
-        (self.state, reward, done, self.actions())
+        self.update_actions();
+        (self.state, reward, done, &self.actions)
+    }
+}
+
+impl traits::Reversible for Environment {
+    fn undo(&mut self) -> Option<Action> {
+        let applied = self.history.pop()?;
+        self.state = applied.state;
+        self.available_positions = applied.available_positions;
+        self.available_pieces = applied.available_pieces;
+        self.update_actions();
+        Some(applied.action)
+    }
+}
+
+impl Variant for Environment {
+    fn variant(rules: Rules, opening: Opening) -> Self {
+        Environment::with_rules(rules).opening(opening)
+    }
+}
+
+impl traits::Simulator for Environment {
+    fn from_state(state: &State) -> Self {
+        Environment::from_state(*state)
     }
 }
 
-fn remove_item<T: PartialEq>(vec: &mut Vec<T>, item: &T) -> bool {
-    vec.iter()
-        .position(|el| el == item)
-        .map(|i| vec.remove(i))
-        .is_some()
+/// Iterate over the indexes of the bits set in the given mask
+pub(crate) fn bits(mut mask: u16) -> impl Iterator<Item = u8> {
+    std::iter::from_fn(move || {
+        if mask == 0 {
+            None
+        } else {
+            let cell = mask.trailing_zeros() as u8;
+            mask &= mask - 1;
+            Some(cell)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::{Canonical, Reversible};
+
+    #[test]
+    fn positions_and_actions_give_the_same_environment() {
+        for game in 0..4 {
+            let mut env = Environment::new();
+            env.reset();
+            let mut actions = Vec::new();
+            for i in 0..12 {
+                let action = env.actions()[(5 * i + game) % env.actions().len()];
+                actions.push(action);
+                if env.step(action).2 {
+                    break;
+                }
+
+                let from_position =
+                    Environment::from_position(&env.state().to_string(), Rules::standard())
+                        .unwrap();
+                let from_actions = Environment::from_actions(&actions, Rules::standard()).unwrap();
+                for other in [from_position, from_actions] {
+                    assert_eq!(other.state(), env.state());
+                    assert_eq!(other.available_positions(), env.available_positions());
+                    assert_eq!(other.available_pieces(), env.available_pieces());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn records_are_replayed_with_their_tags() {
+        let mut record: GameRecord<Action> = "1. a1 BSQF\n2. b1 BSRH\n3. c1 BSRF\n4. d1 WTRF\n1-0"
+            .parse()
+            .unwrap();
+        record.set_tag("Rules", "squares");
+        record.set_tag("Opening", "BTQH");
+        let env = Environment::from_record(&record, Some(1), Rules::standard()).unwrap();
+        assert_eq!(env.state().rules(), Rules::advanced());
+        assert_eq!(
+            env.state().piece("a1".parse().unwrap()),
+            "BTQH".parse().ok()
+        );
+        assert!(Environment::from_record(&record, Some(5), Rules::standard()).is_err());
+
+        // The fourth action ends the game, with the default opening
+        record.tags.clear();
+        record.actions.push("a2 WTRH".parse().unwrap());
+        assert!(Environment::from_record(&record, Some(4), Rules::standard()).is_ok());
+        match Environment::from_record(&record, None, Rules::standard()) {
+            Err(PositionError::IllegalAction {
+                index: 4, error, ..
+            }) => {
+                assert_eq!(error, StepError::GameOver)
+            }
+            _ => panic!("actions after the end of the game are accepted"),
+        }
+
+        record.set_tag("Opening", "random");
+        assert!(Environment::from_record(&record, Some(4), Rules::standard()).is_err());
+    }
+
+    #[test]
+    fn undo_restores_the_environment() {
+        for game in 0..8 {
+            let mut env = Environment::new();
+            env.reset();
+            for i in 0.. {
+                let (state, actions) = (env.state(), env.actions().to_vec());
+                let action = actions[(3 * i + game) % actions.len()];
+                let next_state = env.step(action).0;
+                assert_eq!(env.undo(), Some(action));
+                assert_eq!(env.state(), state);
+                assert_eq!(env.actions(), &actions[..]);
+                assert_eq!(env.history().count(), i);
+
+                let (again_state, _, done, _) = env.step(action);
+                assert_eq!(again_state, next_state);
+                if done {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn canonical_is_invariant_under_symmetries() {
+        let all_rules = [
+            Rules::standard(),
+            Rules::advanced(),
+            Rules {
+                squares: true,
+                wrap_around: true,
+                ..Rules::standard()
+            },
+        ];
+        for &rules in &all_rules {
+            let symmetries: Vec<Symmetry> = (0..16)
+                .flat_map(|piece| Symmetry::normalizing(Piece::from(piece), rules).step_by(331))
+                .collect();
+            let mut env = Environment::with_rules(rules);
+            for game in 0..2 {
+                env.reset();
+                for i in 0.. {
+                    let state = env.state();
+                    let (canonical, _) = state.canonical();
+                    for symmetry in &symmetries {
+                        let (other, _) = state.transformed(symmetry).canonical();
+                        assert_eq!(other, canonical, "{} under {:?}", state, symmetry);
+                    }
+
+                    let action = env.actions()[(7 * i + game) % env.actions().len()];
+                    if env.step(action).2 {
+                        break;
+                    }
+                }
+            }
+        }
+    }
 }
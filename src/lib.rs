@@ -0,0 +1,18 @@
+pub mod board;
+pub mod environment;
+pub mod mcts;
+pub mod persist;
+pub mod player;
+pub mod player_spec;
+pub mod q_table;
+pub mod random;
+pub mod record;
+pub mod replay;
+pub mod rules;
+pub mod simple_players;
+pub mod solver;
+pub mod split;
+pub mod symmetry;
+pub mod terminal;
+pub mod train;
+pub mod traits;
@@ -1,24 +1,779 @@
-mod board;
-mod environment;
-mod player;
-mod simple_players;
-mod train;
-mod traits;
+use clap::{Args, Parser, Subcommand};
+use quarto_rs::board::Action;
+use quarto_rs::environment::*;
+use quarto_rs::persist::{Format, StateKind};
+use quarto_rs::player::*;
+use quarto_rs::player_spec::AnyPlayer;
+use quarto_rs::q_table::*;
+use quarto_rs::record::GameRecord;
+use quarto_rs::replay::{ReplayConfig, Sampling};
+use quarto_rs::rules::Rules;
+use quarto_rs::simple_players::RandomPlayer;
+use quarto_rs::solver::Solver;
+use quarto_rs::split::{SplitAction, SplitEnvironment, SplitState};
+use quarto_rs::terminal;
+use quarto_rs::train::*;
+use quarto_rs::traits::{self, Canonical, Player};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
 
-use environment::*;
-use player::*;
-use train::*;
+#[derive(Parser)]
+#[command(about = "A Q-learning algorithm for Quarto")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Train a q-learning player against previous snapshots of itself
+    Train(TrainArgs),
+    /// Run a duel between two players and print their average score
+    Eval(EvalArgs),
+    /// Play against a player in the terminal
+    Play(PlayArgs),
+    /// Compute the exact value of a position and its best action
+    Solve(SolveArgs),
+    /// Measure how many episodes per second are played
+    Bench(BenchArgs),
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// Number of episodes between random players. A hundredth of them is used to measure the
+    /// training of a q-learning player
+    #[arg(long, default_value_t = 1_000_000)]
+    episodes: u32,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// Players are given as `dummy`, `random`, `mcts[:ITERATIONS[:ROLLOUT[:EXPLORATION]]]`,
+/// `solver[:MAX_NODES]` or the path to a saved player
+#[derive(Args)]
+struct EvalArgs {
+    player_1: String,
+    player_2: String,
+    /// Number of matches, half of them started by each player
+    #[arg(long, default_value_t = 1_000)]
+    episodes: u32,
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Write the record of every match to this file
+    #[arg(long)]
+    records: Option<PathBuf>,
+    #[command(flatten)]
+    rules: RulesArgs,
+}
+
+#[derive(Args)]
+struct PlayArgs {
+    /// The opponent: `dummy`, `random`, `mcts[:ITERATIONS[:ROLLOUT[:EXPLORATION]]]`,
+    /// `solver[:MAX_NODES]` or the path to a saved player
+    #[arg(long, default_value = "mcts")]
+    opponent: String,
+    /// Let the opponent take the first action
+    #[arg(long)]
+    opponent_starts: bool,
+    #[arg(long)]
+    seed: Option<u64>,
+    #[command(flatten)]
+    rules: RulesArgs,
+}
+
+#[derive(Args)]
+struct RulesArgs {
+    /// Four pieces sharing an attribute in a 2x2 square also win (advanced variant)
+    #[arg(long)]
+    squares: bool,
+    /// With `--squares`, the squares that wrap around the edges of the board also win
+    #[arg(long, requires = "squares")]
+    wrap_around: bool,
+}
+
+impl RulesArgs {
+    fn rules(&self) -> Rules {
+        Rules {
+            squares: self.squares,
+            wrap_around: self.wrap_around,
+            ..Rules::default()
+        }
+    }
+}
+
+/// The position is the start of the game, unless given by one of `--moves`, `--position` or
+/// `--record`
+#[derive(Args)]
+struct SolveArgs {
+    /// The actions played from the start of the game, separated by commas. Example: "c3 WTRF,a1
+    /// BSQH"
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["position", "record"])]
+    moves: Vec<Action>,
+    /// The board and the reserve piece. Example: "WTRF.../..../BSQFWTQH../.... BTRH"
+    #[arg(long, conflicts_with = "record")]
+    position: Option<String>,
+    /// Replay the actions of the game record in this file. Its `Rules` tag, if any, takes precedence
+    /// over `--squares` and `--wrap-around`
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Only replay this many actions of the record
+    #[arg(long, requires = "record")]
+    ply: Option<usize>,
+    /// Give up after visiting this many nodes
+    #[arg(long)]
+    max_nodes: Option<u64>,
+    /// Print the value of every valid action
+    #[arg(long)]
+    all: bool,
+    /// Also print the action that this player would take
+    #[arg(long)]
+    player: Option<String>,
+    #[command(flatten)]
+    rules: RulesArgs,
+}
+
+impl SolveArgs {
+    fn environment(&self) -> Result<Environment, String> {
+        let env = if let Some(position) = &self.position {
+            Environment::from_position(position, self.rules.rules())
+        } else if let Some(path) = &self.record {
+            let text =
+                fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let record: GameRecord<Action> = text
+                .parse()
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            Environment::from_record(&record, self.ply, self.rules.rules())
+        } else {
+            Environment::from_actions(&self.moves, self.rules.rules())
+        };
+        env.map_err(|e| e.to_string())
+    }
+}
+
+/// All the parameters of a training run. They can be read from a JSON file and then overridden by
+/// command-line flags
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct TrainConfig {
+    train_episodes: u32,
+    eval_episodes: u32,
+    cycles: u32,
+    opponent_epsilon: f32,
+    stats_file: String,
+    checkpoint: Option<CheckpointConfig>,
+    /// A random seed is picked when missing
+    seed: Option<u64>,
+    /// Where the trained player is saved
+    output: PathBuf,
+    /// Split every turn into two actions: placing the reserve piece, then giving a piece
+    split_turns: bool,
+    rules: Rules,
+    opening: Opening,
+    /// Train a `DoubleQLearningPlayer` instead of a `QLearningPlayer`
+    double_q_learning: bool,
+    player: QLearningConfig,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        TrainConfig {
+            train_episodes: 1_000_000,
+            eval_episodes: 1_000,
+            cycles: 100,
+            opponent_epsilon: 0.1,
+            stats_file: "stats_1m.jsonl".to_owned(),
+            checkpoint: Some(CheckpointConfig {
+                every_cycles: 5,
+                file_name: "checkpoint_1m.bin".to_owned(),
+            }),
+            seed: None,
+            output: PathBuf::from("player_1m.bin"),
+            split_turns: false,
+            rules: Rules::standard(),
+            opening: Opening::default(),
+            double_q_learning: false,
+            player: QLearningConfig {
+                memory_budget: Some(MemoryBudget::new(
+                    4 << 30,
+                    Eviction::LeastRecentlyVisited,
+                    0.9,
+                )),
+                ..QLearningConfig::default()
+            },
+        }
+    }
+}
+
+#[derive(Args)]
+struct TrainArgs {
+    /// Read the parameters from a JSON file. Flags override its values
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Print the parameters as JSON and exit, to start a config file
+    #[arg(long)]
+    print_config: bool,
+    /// Continue from the checkpoint file if it exists. The saved parameters (including the rules and
+    /// the opening) are used and all other flags are ignored, except `--split-turns` and
+    /// `--double-q-learning` that must be the same as in the first run
+    #[arg(long)]
+    resume: bool,
+
+    /// Training episodes per cycle
+    #[arg(long)]
+    train_episodes: Option<u32>,
+    /// Evaluation episodes per cycle
+    #[arg(long)]
+    eval_episodes: Option<u32>,
+    #[arg(long)]
+    cycles: Option<u32>,
+    /// Probability of the adversary taking a random action
+    #[arg(long)]
+    opponent_epsilon: Option<f32>,
+    #[arg(long)]
+    stats_file: Option<String>,
+    #[arg(long)]
+    checkpoint_file: Option<String>,
+    /// Save a checkpoint every this many cycles
+    #[arg(long)]
+    checkpoint_every: Option<u32>,
+    /// Do not save checkpoints
+    #[arg(long, conflicts_with_all = ["checkpoint_file", "checkpoint_every"])]
+    no_checkpoint: bool,
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Where to save the trained player (`.json` files are saved in JSON)
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Place the piece and give the next one in two separate actions, instead of a single one
+    #[arg(long)]
+    split_turns: bool,
+    #[command(flatten)]
+    rules: RulesArgs,
+    /// Wins must be claimed with an action, and missed ones can be claimed by the opponent. Needs
+    /// `--split-turns`
+    #[arg(long)]
+    calling: bool,
+    /// The first piece to place: a piece like `BSQH`, `random`, or `choice` to let the first player
+    /// give it (needs `--split-turns`)
+    #[arg(long)]
+    opening: Option<Opening>,
+    /// Learn two q-tables, each evaluating the best actions of the other, to avoid overestimating
+    /// the q-values
+    #[arg(long)]
+    double_q_learning: bool,
+
+    /// Initial probability of taking a random action
+    #[arg(long)]
+    epsilon: Option<f32>,
+    /// How epsilon evolves, in JSON. Example: {"linear":{"episodes":1000000,"min":0.1}}
+    #[arg(long, value_parser = parse_json::<Schedule>)]
+    exploration: Option<Schedule>,
+    /// Initial learning rate
+    #[arg(long)]
+    alpha: Option<f32>,
+    /// How alpha evolves, in JSON. Example: {"inverse_visits":{"min":0.01}}
+    #[arg(long, value_parser = parse_json::<Schedule>)]
+    learning_rate: Option<Schedule>,
+    /// Discount factor
+    #[arg(long)]
+    gamma: Option<f32>,
+    /// What the q-values are updated towards: q-learning, sarsa or expected-sarsa
+    #[arg(long, value_parser = parse_target)]
+    target: Option<Target>,
+    /// Update each q-value with the rewards of the next N actions, then the value of the state
+    /// reached
+    #[arg(long, value_name = "N", conflicts_with = "lambda")]
+    n_step: Option<u32>,
+    /// Update all the earlier q-values of the episode after each action, with eligibility traces
+    /// decaying by gamma * LAMBDA
+    #[arg(long, value_name = "LAMBDA")]
+    lambda: Option<f32>,
+    /// Bound the q-table memory, in MiB
+    #[arg(long, conflicts_with = "no_memory_budget")]
+    max_memory_mib: Option<usize>,
+    /// Which states are evicted first: least-recently-visited, lowest-hit-count or deepest-first
+    #[arg(long, value_parser = parse_eviction)]
+    eviction: Option<Eviction>,
+    /// Fraction of the memory budget to shrink to when it is exceeded, in (0, 1]
+    #[arg(long)]
+    shrink_to: Option<f32>,
+    /// Do not bound the q-table memory
+    #[arg(long)]
+    no_memory_budget: bool,
+    /// Keep the last transitions in a replay buffer of this size
+    #[arg(long)]
+    replay_capacity: Option<usize>,
+    /// Transitions replayed after every action (can be fractional)
+    #[arg(long)]
+    replay_ratio: Option<f32>,
+    /// How replayed transitions are picked: uniform or prioritized (by the error of their last
+    /// update)
+    #[arg(long, value_parser = parse_sampling)]
+    replay_sampling: Option<Sampling>,
+}
+
+impl TrainArgs {
+    /// Read the config file (if any) and apply the flags on top of it
+    fn config(&self) -> Result<TrainConfig, String> {
+        let mut config = match &self.config {
+            Some(path) => {
+                let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                serde_json::from_reader(file).map_err(|e| format!("{}: {}", path.display(), e))?
+            }
+            None => TrainConfig::default(),
+        };
+
+        override_with(&mut config.train_episodes, self.train_episodes);
+        override_with(&mut config.eval_episodes, self.eval_episodes);
+        override_with(&mut config.cycles, self.cycles);
+        override_with(&mut config.opponent_epsilon, self.opponent_epsilon);
+        override_with(&mut config.stats_file, self.stats_file.clone());
+        if self.seed.is_some() {
+            config.seed = self.seed;
+        }
+        override_with(&mut config.output, self.output.clone());
+        config.split_turns |= self.split_turns;
+        config.rules.squares |= self.rules.squares;
+        config.rules.wrap_around |= self.rules.wrap_around;
+        config.rules.calling |= self.calling;
+        override_with(&mut config.opening, self.opening);
+        config.double_q_learning |= self.double_q_learning;
+        if config.rules.calling && !config.split_turns {
+            return Err("the calling rule needs split turns".to_owned());
+        }
+        if config.opening == Opening::Choice && !config.split_turns {
+            return Err("choosing the opening needs split turns".to_owned());
+        }
+
+        if self.no_checkpoint {
+            config.checkpoint = None;
+        } else if self.checkpoint_file.is_some() || self.checkpoint_every.is_some() {
+            let checkpoint = config.checkpoint.get_or_insert(CheckpointConfig {
+                every_cycles: 5,
+                file_name: "checkpoint.bin".to_owned(),
+            });
+            override_with(&mut checkpoint.file_name, self.checkpoint_file.clone());
+            override_with(&mut checkpoint.every_cycles, self.checkpoint_every);
+        }
+
+        let player = &mut config.player;
+        override_with(&mut player.epsilon, self.epsilon);
+        override_with(&mut player.exploration, self.exploration);
+        override_with(&mut player.alpha, self.alpha);
+        override_with(&mut player.learning_rate, self.learning_rate);
+        override_with(&mut player.gamma, self.gamma);
+        override_with(&mut player.target, self.target);
+        if let Some(n) = self.n_step {
+            player.returns = Returns::NStep { n };
+        }
+        if let Some(lambda) = self.lambda {
+            player.returns = Returns::Lambda { lambda };
+        }
+        if self.no_memory_budget {
+            player.memory_budget = None;
+        } else if self.max_memory_mib.is_some()
+            || self.eviction.is_some()
+            || self.shrink_to.is_some()
+        {
+            let budget = player.memory_budget.get_or_insert(MemoryBudget::new(
+                4 << 30,
+                Eviction::LeastRecentlyVisited,
+                0.9,
+            ));
+            override_with(
+                &mut budget.max_bytes,
+                self.max_memory_mib.map(|mib| mib << 20),
+            );
+            override_with(&mut budget.eviction, self.eviction);
+            override_with(&mut budget.shrink_to, self.shrink_to);
+        }
+        if self.replay_capacity.is_some()
+            || self.replay_ratio.is_some()
+            || self.replay_sampling.is_some()
+        {
+            let replay = player.replay.get_or_insert(ReplayConfig {
+                capacity: 100_000,
+                ratio: 1.,
+                sampling: Sampling::Uniform,
+            });
+            override_with(&mut replay.capacity, self.replay_capacity);
+            override_with(&mut replay.ratio, self.replay_ratio);
+            override_with(&mut replay.sampling, self.replay_sampling);
+        }
+
+        if let Some(checkpoint) = &config.checkpoint {
+            if checkpoint.every_cycles == 0 {
+                return Err("the checkpoint interval must be at least one cycle".to_owned());
+            }
+        }
+        match config.player.returns {
+            Returns::NStep { n: 0 } => {
+                return Err("n-step returns need at least one step".to_owned())
+            }
+            Returns::Lambda { lambda } if !(0. ..=1.).contains(&lambda) => {
+                return Err("lambda must be between 0 and 1".to_owned())
+            }
+            _ => {}
+        }
+        if let Some(budget) = config.player.memory_budget {
+            if !(budget.shrink_to > 0. && budget.shrink_to <= 1.) {
+                return Err(
+                    "the table must shrink to a fraction of its budget in (0, 1]".to_owned(),
+                );
+            }
+        }
+        if let Some(replay) = config.player.replay {
+            if replay.capacity == 0 || !(replay.ratio > 0. && replay.ratio.is_finite()) {
+                return Err(
+                    "the replay buffer needs a capacity and a finite positive ratio".to_owned(),
+                );
+            }
+        }
+        if config.double_q_learning
+            && (config.player.target != Target::QLearning
+                || config.player.returns != Returns::NStep { n: 1 }
+                || config.player.replay.is_some())
+        {
+            return Err(
+                "double q-learning only supports one-step q-learning updates, without replay"
+                    .to_owned(),
+            );
+        }
+
+        Ok(config)
+    }
+}
+
+fn override_with<T>(value: &mut T, flag: Option<T>) {
+    if let Some(flag) = flag {
+        *value = flag;
+    }
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(value: &str) -> Result<T, String> {
+    serde_json::from_str(value).map_err(|e| e.to_string())
+}
+
+fn parse_target(value: &str) -> Result<Target, String> {
+    match value {
+        "q-learning" => Ok(Target::QLearning),
+        "sarsa" => Ok(Target::Sarsa),
+        "expected-sarsa" => Ok(Target::ExpectedSarsa),
+        _ => Err(format!("unknown update target: {}", value)),
+    }
+}
+
+fn parse_sampling(value: &str) -> Result<Sampling, String> {
+    match value {
+        "uniform" => Ok(Sampling::Uniform),
+        "prioritized" => Ok(Sampling::Prioritized),
+        _ => Err(format!("unknown replay sampling: {}", value)),
+    }
+}
+
+fn parse_eviction(value: &str) -> Result<Eviction, String> {
+    match value {
+        "least-recently-visited" => Ok(Eviction::LeastRecentlyVisited),
+        "lowest-hit-count" => Ok(Eviction::LowestHitCount),
+        "deepest-first" => Ok(Eviction::DeepestFirst),
+        _ => Err(format!("unknown eviction policy: {}", value)),
+    }
+}
 
 fn main() {
+    let result = match Cli::parse().command {
+        Command::Train(args) => run_train(args),
+        Command::Eval(args) => run_eval(args),
+        Command::Play(args) => run_play(args),
+        Command::Solve(args) => run_solve(args),
+        Command::Bench(args) => run_bench(args),
+    };
+    if let Err(error) = result {
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    }
+}
+
+fn run_train(args: TrainArgs) -> Result<(), String> {
+    let config = args.config()?;
+    if args.print_config {
+        println!("{}", serde_json::to_string_pretty(&config).unwrap());
+        return Ok(());
+    }
+
+    if config.split_turns {
+        train_and_save::<SplitEnvironment>(&config, args.resume)
+    } else {
+        train_and_save::<Environment>(&config, args.resume)
+    }
+}
+
+/// Train a player in an environment of type `E` (or resume its training) and save it
+fn train_and_save<E>(config: &TrainConfig, resume_run: bool) -> Result<(), String>
+where
+    E: traits::Environment + Variant,
+    E::State: Canonical<E::Action> + StateKind + Serialize + DeserializeOwned,
+{
+    let output = &config.output;
+    let saved = if config.double_q_learning {
+        let player = train_player::<E, _>(config, resume_run, DoubleQLearningPlayer::with_config)?;
+        player.save(output, Format::from_path(output))
+    } else {
+        let player = train_player::<E, _>(config, resume_run, QLearningPlayer::with_config)?;
+        player.save(output, Format::from_path(output))
+    };
+    saved.map_err(|e| format!("{}: {}", output.display(), e))?;
+    println!("Saved player to {}", output.display());
+    Ok(())
+}
+
+/// Train the player created by `new_player`, or resume the training of the checkpointed one
+fn train_player<E, P>(
+    config: &TrainConfig,
+    resume_run: bool,
+    new_player: impl FnOnce(QLearningConfig) -> P,
+) -> Result<P, String>
+where
+    E: traits::Environment + Variant,
+    E::State: StateKind,
+    P: traits::LearningPlayer<E::State, E::Action> + Serialize + DeserializeOwned,
+    P::Freezed: Serialize + DeserializeOwned,
+{
+    match &config.checkpoint {
+        Some(checkpoint) if resume_run && Path::new(&checkpoint.file_name).exists() => {
+            resume::<_, _, _, E>(&checkpoint.file_name)
+                .map_err(|e| format!("{}: {}", checkpoint.file_name, e))
+        }
+        _ => {
+            let mut player = new_player(config.player.clone());
+            train::<_, _, _, E>(
+                config.rules,
+                config.opening,
+                &mut player,
+                config.train_episodes,
+                config.eval_episodes,
+                config.cycles,
+                config.opponent_epsilon,
+                &config.stats_file,
+                config.checkpoint.clone(),
+                config.seed.unwrap_or_else(rand::random),
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(player)
+        }
+    }
+}
+
+/// Load a player to play with the given rules, which must be the ones it was trained with
+fn load_player(spec: &str, rules: Rules) -> Result<AnyPlayer, String> {
+    let player = AnyPlayer::from_spec(spec).map_err(|e| format!("{}: {}", spec, e))?;
+    match player.rules() {
+        Some(player_rules) if player_rules != rules => Err(format!(
+            "{} was trained with the rules \"{}\", not \"{}\"",
+            spec, player_rules, rules
+        )),
+        _ => Ok(player),
+    }
+}
+
+fn run_eval(args: EvalArgs) -> Result<(), String> {
+    if !args.episodes.is_multiple_of(2) {
+        return Err("the number of episodes must be even".to_owned());
+    }
+    let rules = args.rules.rules();
+    let mut player_1 = load_player(&args.player_1, rules)?;
+    let mut player_2 = load_player(&args.player_2, rules)?;
+    let seed = args.seed.unwrap_or_else(rand::random);
+    player_1.seed(seed);
+    player_2.seed(seed.wrapping_add(1));
+
+    let mut env = Environment::with_rules(rules);
+    // Same as run_duel(), keeping how each match was won
+    let mut score = 0.;
+    let mut records = Vec::new();
+    let mut outcomes = Vec::new();
+    for episode in 0..args.episodes {
+        let mut record = GameRecord::new();
+        if episode % 2 == 0 {
+            score += run_match(&mut env, &mut player_1, &mut player_2, Some(&mut record));
+        } else {
+            score -= run_match(&mut env, &mut player_2, &mut player_1, Some(&mut record));
+        }
+        outcomes.push(env.outcome().ok_or("a match did not end")?);
+        records.push(record);
+    }
+    let score = score / args.episodes as f32;
+
+    if let Some(path) = &args.records {
+        write_records(
+            path,
+            records,
+            &outcomes,
+            rules,
+            &args.player_1,
+            &args.player_2,
+        )
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+
+    println!("Seed = {}", seed);
+    println!("Avg score of {} = {}", args.player_1, score);
+    print_quarto_stats(&outcomes);
+    for (spec, player) in &[(&args.player_1, &player_1), (&args.player_2, &player_2)] {
+        if let Some(stats) = player.stats() {
+            println!("Stats of {} = {}", spec, stats);
+        }
+    }
+    Ok(())
+}
+
+/// Print how many Quartos were made with each kind of line and each attribute
+fn print_quarto_stats(outcomes: &[Outcome]) {
+    let mut kinds = BTreeMap::new();
+    let mut traits = BTreeMap::new();
+    for quarto in outcomes.iter().flat_map(|outcome| &outcome.quartos) {
+        *kinds.entry(quarto.kind).or_insert(0) += 1;
+        for &t in &quarto.traits {
+            *traits.entry(t).or_insert(0) += 1;
+        }
+    }
+    let kinds: Vec<String> = kinds.iter().map(|(k, n)| format!("{} {}", k, n)).collect();
+    let traits: Vec<String> = traits.iter().map(|(t, n)| format!("{} {}", t, n)).collect();
+    println!("Quartos by line = {}", kinds.join(", "));
+    println!("Quartos by attribute = {}", traits.join(", "));
+}
+
+/// Write the records of a duel, where the players alternate starting the matches, with the rules,
+/// the first piece and the Quartos that ended them
+fn write_records(
+    path: &Path,
+    records: Vec<GameRecord<Action>>,
+    outcomes: &[Outcome],
+    rules: Rules,
+    player_1: &str,
+    player_2: &str,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for (i, (mut record, outcome)) in records.into_iter().zip(outcomes).enumerate() {
+        let (first, second) = if i % 2 == 0 {
+            (player_1, player_2)
+        } else {
+            (player_2, player_1)
+        };
+        record.set_tag("Player1", first);
+        record.set_tag("Player2", second);
+        record.set_tag("Rules", &rules.to_string());
+        // Duels always start with the default opening
+        record.set_tag("Opening", &Opening::default().to_string());
+        if !outcome.quartos.is_empty() {
+            let quartos: Vec<String> = outcome.quartos.iter().map(|q| q.to_string()).collect();
+            record.set_tag("Quarto", &quartos.join("; "));
+        }
+        writeln!(file, "{}", record)?;
+    }
+    file.flush()
+}
+
+fn run_play(args: PlayArgs) -> Result<(), String> {
+    let rules = args.rules.rules();
+    let mut opponent = load_player(&args.opponent, rules)?;
+    opponent.seed(args.seed.unwrap_or_else(rand::random));
+    let stdin = io::stdin();
+    terminal::play(
+        &mut opponent,
+        rules,
+        !args.opponent_starts,
+        stdin.lock(),
+        io::stdout(),
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn run_solve(args: SolveArgs) -> Result<(), String> {
+    let env = args.environment()?;
+    let state = env.state();
+    if env.is_over() {
+        return Err("the game is already over".to_owned());
+    }
+    println!("{}", terminal::render(&state));
+    println!("Position = {}", state);
+
+    if let Some(spec) = &args.player {
+        let mut player = load_player(spec, state.rules())?;
+        let action = player.take_action(state, env.actions());
+        println!("{} would play {}", spec, action);
+    }
+
+    let mut solver = match args.max_nodes {
+        Some(max_nodes) => Solver::with_node_limit(max_nodes),
+        None => Solver::new(),
+    };
+    if args.all {
+        let values = solver
+            .action_values(&env)
+            .ok_or("node limit reached before solving the position")?;
+        for (action, value) in values {
+            println!("{} {:?}", action, value);
+        }
+    } else {
+        let solution = solver
+            .solve(&env)
+            .ok_or("node limit reached before solving the position")?;
+        println!(
+            "Value = {:?}, best action = {}",
+            solution.value, solution.action
+        );
+    }
+    println!("Visited {} nodes", solver.nodes());
+    Ok(())
+}
+
+fn run_bench(args: BenchArgs) -> Result<(), String> {
     let mut env = Environment::new();
+
+    let mut player_1 = RandomPlayer::new();
+    let mut player_2 = RandomPlayer::new();
+    Player::<State, Action>::seed(&mut player_1, args.seed);
+    Player::<State, Action>::seed(&mut player_2, args.seed.wrapping_add(1));
+    let episodes = args.episodes & !1;
+    let start = Instant::now();
+    run_duel(&mut env, &mut player_1, &mut player_2, episodes, None);
+    print_speed("Random players", episodes, start);
+
     let mut player = QLearningPlayer::new();
-    train(
-        &mut env,
-        &mut player,
-        1_000_000,
-        1_000,
-        100,
-        0.1,
-        "stats_1m.jsonl",
+    let mut adversary = QLearningPlayer::new();
+    Player::<State, Action>::seed(&mut player, args.seed);
+    Player::<State, Action>::seed(&mut adversary, args.seed.wrapping_add(1));
+    let episodes = (args.episodes / 100) & !1;
+    let start = Instant::now();
+    run_duel(&mut env, &mut player, &mut adversary, episodes, None);
+    print_speed("Q-learning players", episodes, start);
+
+    let mut env = SplitEnvironment::new();
+    let mut player = QLearningPlayer::new();
+    let mut adversary = QLearningPlayer::new();
+    Player::<SplitState, SplitAction>::seed(&mut player, args.seed);
+    Player::<SplitState, SplitAction>::seed(&mut adversary, args.seed.wrapping_add(1));
+    let start = Instant::now();
+    run_duel(&mut env, &mut player, &mut adversary, episodes, None);
+    print_speed("Q-learning players (split turns)", episodes, start);
+    Ok(())
+}
+
+fn print_speed(name: &str, episodes: u32, start: Instant) {
+    let seconds = start.elapsed().as_secs_f64();
+    println!(
+        "{}: {} episodes in {:.2}s = {:.0} episodes/s",
+        name,
+        episodes,
+        seconds,
+        episodes as f64 / seconds
     );
 }
@@ -0,0 +1,322 @@
+//! A Monte Carlo Tree Search player, using UCT to select nodes
+//!
+//! Every simulation ends in a win, draw or loss, scored as 1, 0.5 or 0 for the player that took
+//! the action leading to each node, so the exploration constant does not depend on the scale of
+//! the rewards of the environment.
+
+use crate::random::{self, SeededRng};
+use crate::traits::*;
+use rand::prelude::*;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// How much search is done for each action
+#[derive(Copy, Clone, Debug)]
+pub enum Budget {
+    Iterations(u32),
+    Time(Duration),
+}
+
+/// How simulations are played after leaving the tree
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Rollout {
+    /// Uniformly random actions
+    Random,
+    /// Take an action that ends the game with a positive reward if there is one, otherwise a
+    /// random action
+    Greedy,
+}
+
+struct Node<A> {
+    /// The action that leads from the parent to this node
+    action: Option<A>,
+    children: Vec<usize>,
+    untried: Vec<A>,
+    visits: u32,
+    /// Sum of the results, from the point of view of the player that took `action`
+    wins: f32,
+    /// Whether `action` was taken by the player at the root
+    by_root: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MctsStats {
+    pub actions: u32,
+    pub iterations: u64,
+    pub nodes: u64,
+}
+
+pub struct MctsPlayer<E: Simulator> {
+    budget: Budget,
+    exploration: f32,
+    rollout: Rollout,
+    stats: MctsStats,
+    rng: SeededRng,
+    _e: std::marker::PhantomData<E>,
+}
+
+impl<E: Simulator + Reversible> MctsPlayer<E> {
+    pub fn new(budget: Budget) -> Self {
+        MctsPlayer {
+            budget,
+            exploration: std::f32::consts::SQRT_2,
+            rollout: Rollout::Random,
+            stats: MctsStats {
+                actions: 0,
+                iterations: 0,
+                nodes: 0,
+            },
+            rng: random::from_entropy(),
+            _e: std::marker::PhantomData,
+        }
+    }
+
+    /// Set the UCT exploration constant (default: sqrt(2))
+    pub fn exploration(mut self, exploration: f32) -> Self {
+        self.exploration = exploration;
+        self
+    }
+
+    pub fn rollout(mut self, rollout: Rollout) -> Self {
+        self.rollout = rollout;
+        self
+    }
+
+    /// Run one iteration: select a leaf, expand it, simulate until the end of the game and
+    /// propagate the result back. The environment is restored to the root position at the end
+    fn iterate(&self, env: &mut E, tree: &mut Vec<Node<E::Action>>, rng: &mut SeededRng) {
+        let mut path = vec![0];
+        // The score of the player at the root minus the score of its opponent
+        let mut score = 0.;
+        let mut done = false;
+        let mut steps = 0;
+        let mut root_turn = true;
+
+        // Selection
+        let mut node = 0;
+        while tree[node].untried.is_empty() && !tree[node].children.is_empty() {
+            node = self.select_child(tree, node);
+            let action = tree[node].action.clone().unwrap();
+            let (state, reward, child_done, _) = env.step(action);
+            steps += 1;
+            score += sign(root_turn) * reward;
+            root_turn = next_turn(root_turn, &state);
+            path.push(node);
+            done = child_done;
+        }
+
+        // Expansion
+        if !done && !tree[node].untried.is_empty() {
+            let i = rng.gen_range(0, tree[node].untried.len());
+            let action = tree[node].untried.swap_remove(i);
+            let by_root = root_turn;
+            let (state, reward, child_done, actions) = env.step(action.clone());
+            steps += 1;
+            score += sign(root_turn) * reward;
+            root_turn = next_turn(root_turn, &state);
+            tree.push(Node {
+                action: Some(action),
+                children: Vec::new(),
+                untried: if child_done {
+                    Vec::new()
+                } else {
+                    actions.to_vec()
+                },
+                visits: 0,
+                wins: 0.,
+                by_root,
+            });
+            let child = tree.len() - 1;
+            tree[node].children.push(child);
+            path.push(child);
+            done = child_done;
+
+            // Simulation
+            let mut actions = tree[child].untried.clone();
+            while !done {
+                let action = self.rollout_action(env, &actions, rng);
+                let (state, reward, step_done, next_actions) = env.step(action);
+                steps += 1;
+                score += sign(root_turn) * reward;
+                root_turn = next_turn(root_turn, &state);
+                // Copied, since the greedy rollout needs to step the environment while looking
+                // at them
+                actions.clear();
+                actions.extend_from_slice(next_actions);
+                done = step_done;
+            }
+        }
+
+        // Backpropagation
+        for _ in 0..steps {
+            env.undo();
+        }
+
+        let result = if score > 0. {
+            1.
+        } else if score < 0. {
+            0.
+        } else {
+            0.5
+        };
+        for &node in &path {
+            tree[node].visits += 1;
+            tree[node].wins += if tree[node].by_root {
+                result
+            } else {
+                1. - result
+            };
+        }
+    }
+
+    fn select_child(&self, tree: &[Node<E::Action>], node: usize) -> usize {
+        let log_visits = (tree[node].visits as f32).ln();
+        let uct = |child: usize| {
+            let child = &tree[child];
+            child.wins / child.visits as f32
+                + self.exploration * (log_visits / child.visits as f32).sqrt()
+        };
+        let mut best = tree[node].children[0];
+        let mut best_uct = uct(best);
+        for &child in &tree[node].children[1..] {
+            let child_uct = uct(child);
+            if child_uct > best_uct {
+                best = child;
+                best_uct = child_uct;
+            }
+        }
+        best
+    }
+
+    fn rollout_action(&self, env: &mut E, actions: &[E::Action], rng: &mut SeededRng) -> E::Action {
+        if self.rollout == Rollout::Greedy {
+            for action in actions {
+                let (_, reward, done, _) = env.step(action.clone());
+                env.undo();
+                if done && reward > 0. {
+                    return action.clone();
+                }
+            }
+        }
+        actions.choose(rng).unwrap().clone()
+    }
+}
+
+impl<E: Simulator + Reversible> Player<E::State, E::Action> for MctsPlayer<E> {
+    type Stats = MctsStats;
+
+    fn take_action(&mut self, state: E::State, actions: &[E::Action]) -> E::Action {
+        let mut env = E::from_state(&state);
+        // Moved out while searching, since iterate() borrows self
+        let mut rng = self.rng.clone();
+        let mut tree = vec![Node {
+            action: None,
+            children: Vec::new(),
+            untried: actions.to_vec(),
+            visits: 0,
+            wins: 0.,
+            by_root: false,
+        }];
+
+        let start = Instant::now();
+        let mut iterations = 0;
+        loop {
+            let finished = match self.budget {
+                Budget::Iterations(max) => iterations >= max,
+                Budget::Time(max) => iterations > 0 && start.elapsed() >= max,
+            };
+            if finished {
+                break;
+            }
+            self.iterate(&mut env, &mut tree, &mut rng);
+            iterations += 1;
+        }
+        self.rng = rng;
+
+        self.stats.actions += 1;
+        self.stats.iterations += iterations as u64;
+        self.stats.nodes += tree.len() as u64;
+
+        // Pick the most visited action
+        tree[0]
+            .children
+            .iter()
+            .max_by_key(|&&child| tree[child].visits)
+            .and_then(|&child| tree[child].action.clone())
+            .unwrap_or_else(|| actions[0].clone())
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.rng = random::seeded(seed);
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats.actions = 0;
+        self.stats.iterations = 0;
+        self.stats.nodes = 0;
+    }
+
+    fn stats(&self) -> Option<MctsStats> {
+        Some(self.stats.clone())
+    }
+}
+
+/// Rewards of actions taken by the root player count for it, the others against it
+fn sign(root_turn: bool) -> f32 {
+    if root_turn {
+        1.
+    } else {
+        -1.
+    }
+}
+
+/// Whether the root player takes the action after the one that led to `state`
+fn next_turn<S: State>(root_turn: bool, state: &S) -> bool {
+    root_turn == state.continues_turn()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Action;
+    use crate::environment::Environment;
+    use crate::rules::Rules;
+    use crate::traits::Environment as _;
+
+    fn search(env: &Environment, rollout: Rollout) -> Action {
+        let mut player = MctsPlayer::<Environment>::new(Budget::Iterations(1_000)).rollout(rollout);
+        Player::<_, Action>::seed(&mut player, 7);
+        player.take_action(env.state(), env.actions())
+    }
+
+    #[test]
+    fn finds_an_immediate_win() {
+        // Three black pieces on the fourth row, and a black one to place
+        let env =
+            Environment::from_position("BSQFBTQHBTRH./..../..../.... BSRH", Rules::standard())
+                .unwrap();
+        for &rollout in &[Rollout::Random, Rollout::Greedy] {
+            let action = search(&env, rollout);
+            assert_eq!(action.position.to_string(), "d4");
+        }
+    }
+
+    #[test]
+    fn does_not_give_a_winning_piece() {
+        // Three black pieces on the fourth row and a white one to place: giving a black piece
+        // loses, unless the row is blocked
+        let env =
+            Environment::from_position("BSQFBTQHBTRH./..../..../.... WSRF", Rules::standard())
+                .unwrap();
+        for &rollout in &[Rollout::Random, Rollout::Greedy] {
+            let mut env = env.clone();
+            let action = search(&env, rollout);
+            assert!(!env.step(action).2);
+            for &reply in env.actions() {
+                let mut child = env.clone();
+                let (_, reward, done, _) = child.step(reply);
+                assert!(!(done && reward > 0.), "{} lets {} win", action, reply);
+            }
+        }
+    }
+}
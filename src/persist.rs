@@ -0,0 +1,175 @@
+//! Save and load players to disk
+//!
+//! Files start with a small header (the kind of the saved value and the version of the format),
+//! followed by the payload. The binary format is compact and meant for big q-tables, while the
+//! JSON format is handy to inspect small ones.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Bump this every time the layout of a persisted value changes
+pub const VERSION: u32 = 9;
+
+/// The first bytes of every file in the binary format
+const MAGIC: &[u8; 4] = b"QRTO";
+
+/// A state that names its environment in the kind of the saved files, so that a player trained in
+/// one environment cannot be loaded for another
+pub trait StateKind {
+    const NAME: &'static str;
+}
+
+/// The kind of a value saved for states of type `S`, for example `q-learning player/split`
+pub fn kind_of<S: StateKind>(kind: &str) -> String {
+    format!("{}/{}", kind, S::NAME)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Format {
+    Binary,
+    Json,
+}
+
+impl Format {
+    /// Detect the format from the file extension: `.json` files use JSON, everything else is binary
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension() {
+            Some(extension) if extension == "json" => Format::Json,
+            _ => Format::Binary,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    kind: String,
+    version: u32,
+}
+
+#[derive(Serialize)]
+struct JsonFile<'a, T> {
+    #[serde(flatten)]
+    header: Header,
+    value: &'a T,
+}
+
+#[derive(Deserialize)]
+struct JsonValue<T> {
+    value: T,
+}
+
+/// Save a value of the given kind to a file
+pub fn save<T: Serialize, P: AsRef<Path>>(
+    value: &T,
+    kind: &str,
+    path: P,
+    format: Format,
+) -> io::Result<()> {
+    let header = Header {
+        kind: kind.to_owned(),
+        version: VERSION,
+    };
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        Format::Binary => {
+            writer.write_all(MAGIC)?;
+            bincode::serialize_into(&mut writer, &header).map_err(invalid_data)?;
+            bincode::serialize_into(&mut writer, value).map_err(invalid_data)?;
+        }
+        Format::Json => {
+            serde_json::to_writer(&mut writer, &JsonFile { header, value })?;
+        }
+    }
+    writer.flush()
+}
+
+/// Load a value of the given kind from a file, detecting its format from its contents
+pub fn load<T: DeserializeOwned, P: AsRef<Path>>(kind: &str, path: P) -> io::Result<T> {
+    match read_header(path)? {
+        (header, Payload::Binary(reader)) => {
+            check_header(&header, kind)?;
+            bincode::deserialize_from(reader).map_err(invalid_data)
+        }
+        (header, Payload::Json(contents)) => {
+            check_header(&header, kind)?;
+            let file: JsonValue<T> = serde_json::from_slice(&contents)?;
+            Ok(file.value)
+        }
+    }
+}
+
+/// Read the kind of the value saved in a file, without loading it
+pub fn saved_kind<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    Ok(read_header(path)?.0.kind)
+}
+
+/// What follows the header: the rest of a binary file, or the whole contents of a JSON one
+enum Payload {
+    Binary(BufReader<File>),
+    Json(Vec<u8>),
+}
+
+fn read_header<P: AsRef<Path>>(path: P) -> io::Result<(Header, Payload)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic == MAGIC {
+        let header = bincode::deserialize_from(&mut reader).map_err(invalid_data)?;
+        Ok((header, Payload::Binary(reader)))
+    } else {
+        let mut contents = magic.to_vec();
+        reader.read_to_end(&mut contents)?;
+        let header = serde_json::from_slice(&contents)?;
+        Ok((header, Payload::Json(contents)))
+    }
+}
+
+fn check_header(header: &Header, kind: &str) -> io::Result<()> {
+    if header.kind != kind {
+        Err(invalid_data(format!(
+            "expected a file with {}, found {}",
+            kind, header.kind
+        )))
+    } else if header.version != VERSION {
+        Err(invalid_data(format!(
+            "unsupported format version {} (expected {})",
+            header.version, VERSION
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Serialize a map as a list of entries, since JSON only supports maps with string keys.
+/// Use it with `#[serde(with = "crate::persist::entries")]`
+pub mod entries {
+    use super::*;
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let entries: Vec<(K, V)> = Deserialize::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
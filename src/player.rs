@@ -1,9 +1,134 @@
+use crate::persist::{self, Format, StateKind};
+use crate::q_table::*;
+use crate::random::{self, SeededRng};
+use crate::replay::{ReplayBuffer, ReplayConfig, Transition};
 use crate::traits::*;
 use rand::prelude::*;
-use serde::Serialize;
-use std::collections::HashMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::mem;
+use std::path::Path;
 
-#[derive(Debug, Clone, Serialize)]
+/// The kinds of the saved players, completed with the name of their state by `persist::kind_of()`
+pub const Q_LEARNING_KIND: &str = "q-learning player";
+pub const DOUBLE_Q_LEARNING_KIND: &str = "double q-learning player";
+pub const Q_LEARNED_KIND: &str = "q-learned player";
+
+/// How a hyperparameter evolves during training, starting from its initial value.
+/// Written in JSON as `"constant"` or as an object keyed by the schedule, like
+/// `{"linear":{"episodes":1000000,"min":0.1}}` (internally tagged enums cannot be read back from
+/// the binary format)
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Schedule {
+    Constant,
+    /// Multiply by `decay` after each training episode, down to `min`
+    Exponential {
+        decay: f32,
+        min: f32,
+    },
+    /// Decrease linearly down to `min` over the given number of training episodes
+    Linear {
+        episodes: u32,
+        min: f32,
+    },
+    /// Divide by the number of times the q-value of the state was updated, down to `min`
+    InverseVisits {
+        min: f32,
+    },
+}
+
+impl Schedule {
+    pub fn value(&self, initial: f32, episodes: u32, visits: u32) -> f32 {
+        match *self {
+            Schedule::Constant => initial,
+            Schedule::Exponential { decay, min } => {
+                ((initial as f64) * (decay as f64).powf(episodes as f64)).max(min as f64) as f32
+            }
+            Schedule::Linear {
+                episodes: total,
+                min,
+            } => {
+                let progress = (episodes as f32 / total.max(1) as f32).min(1.);
+                initial + (min - initial) * progress
+            }
+            Schedule::InverseVisits { min } => (initial / (1 + visits) as f32).max(min),
+        }
+    }
+}
+
+/// The value that the q-value of an action is moved towards, after the reward of the action
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// The best q-value of the next state (off-policy)
+    QLearning,
+    /// The q-value of the action taken in the next state (on-policy)
+    Sarsa,
+    /// The q-value expected in the next state when following the epsilon-greedy policy
+    ExpectedSarsa,
+}
+
+/// Which rewards are used to update the q-value of an action
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Returns {
+    /// The discounted rewards of the next `n` actions, followed by the value of the state reached
+    /// after them (see `Target`). With `n = 1`, this is the usual one-step update. The last actions
+    /// of an episode are updated when it ends
+    NStep { n: u32 },
+    /// TD(lambda): after every action, the q-values of all the earlier actions of the episode are
+    /// also updated, weighted by eligibility traces that decay by `gamma * lambda` per action.
+    /// Traces are not cut after random actions
+    Lambda { lambda: f32 },
+}
+
+/// The hyperparameters of `QLearningPlayer`. Missing fields take their default values when
+/// deserialized
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QLearningConfig {
+    /// Initial probability of taking a random action
+    pub epsilon: f32,
+    pub exploration: Schedule,
+    /// Initial learning rate
+    pub alpha: f32,
+    pub learning_rate: Schedule,
+    /// Discount factor
+    pub gamma: f32,
+    /// Only used by `QLearningPlayer`: `DoubleQLearningPlayer` always uses `Target::QLearning`
+    pub target: Target,
+    /// Only used by `QLearningPlayer`: `DoubleQLearningPlayer` always uses one-step returns
+    pub returns: Returns,
+    /// Bound the memory used by the q-table, evicting entries when it grows over the budget
+    pub memory_budget: Option<MemoryBudget>,
+    /// Also update the q-values of past transitions after every action. Only used by
+    /// `QLearningPlayer`, and not counted in the memory budget
+    pub replay: Option<ReplayConfig>,
+}
+
+impl Default for QLearningConfig {
+    fn default() -> Self {
+        QLearningConfig {
+            epsilon: 1.,
+            exploration: Schedule::Exponential {
+                decay: 0.999999,
+                min: 0.1,
+            },
+            alpha: 0.1,
+            learning_rate: Schedule::Constant,
+            gamma: 1.,
+            target: Target::QLearning,
+            returns: Returns::NStep { n: 1 },
+            memory_budget: None,
+            replay: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QLearningStats {
     pub total_actions: u32,
     pub random_actions: u32,
@@ -13,12 +138,19 @@ pub struct QLearningStats {
     pub play_episodes: u32,
     pub q_table_size: u32,
     pub q_table_per_depth: HashMap<u16, u32>,
+    pub evicted: u32,
+    pub evicted_per_depth: HashMap<u16, u32>,
+    /// The number of transitions in the replay buffer
+    pub replay_buffer_size: u32,
+    /// The number of updates of replayed transitions since the start of the training
+    pub replayed_updates: u32,
     pub epsilon: f32,
     pub score: f32,
+    pub config: QLearningConfig,
 }
 
 impl QLearningStats {
-    fn new() -> Self {
+    pub(crate) fn new(config: QLearningConfig) -> Self {
         Self {
             total_actions: 0,
             random_actions: 0,
@@ -28,8 +160,13 @@ impl QLearningStats {
             play_episodes: 0,
             q_table_size: 0,
             q_table_per_depth: HashMap::new(),
+            evicted: 0,
+            evicted_per_depth: HashMap::new(),
+            replay_buffer_size: 0,
+            replayed_updates: 0,
             epsilon: 0.,
             score: 0.,
+            config,
         }
     }
 
@@ -43,113 +180,299 @@ impl QLearningStats {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "S: Serialize", deserialize = "S: DeserializeOwned"))]
 pub struct QLearningPlayer<S: State> {
-    // A map from state, to (hit count, q-value)
-    q_table: HashMap<S, (u32, Vec<f32>)>,
-    epsilon: f32,
-    min_epsilon: f32,
-    epsilon_decay: f32,
-    alpha: f32,
-    gamma: f32,
-    prev_state: Option<S>,
-    prev_action_index: Option<usize>,
+    q_table: QTable<S>,
+    config: QLearningConfig,
+    /// The actions of the current episode whose q-values can still be updated
+    #[serde(skip)]
+    trajectory: VecDeque<Visit<S>>,
+    /// Reused by `canonicalize()`
+    #[serde(skip)]
+    order: Vec<usize>,
+    replay: Option<ReplayBuffer<S>>,
     stats: QLearningStats,
+    // Saved along with the player, so that resumed runs are reproducible
+    rng: SeededRng,
+}
+
+/// An action taken in a canonical state during the current episode
+#[derive(Clone)]
+struct Visit<S> {
+    state: S,
+    action_index: usize,
+    /// The discounted sum of the rewards received since the action (n-step returns only)
+    rewards: f32,
+    /// How many rewards were added to `rewards`
+    num_rewards: u32,
 }
 
 impl<S: State> QLearningPlayer<S> {
     pub fn new() -> Self {
+        Self::with_config(QLearningConfig::default())
+    }
+
+    /// Panics if the returns are invalid: n-step returns need at least one step, and lambda must
+    /// be between 0 and 1
+    pub fn with_config(config: QLearningConfig) -> Self {
+        match config.returns {
+            Returns::NStep { n } => assert!(n > 0, "n-step returns need at least one step"),
+            Returns::Lambda { lambda } => assert!(
+                (0. ..=1.).contains(&lambda),
+                "lambda must be between 0 and 1, not {}",
+                lambda
+            ),
+        }
         let mut player = QLearningPlayer {
-            q_table: HashMap::new(),
-            epsilon: 1.,
-            min_epsilon: 0.1,
-            epsilon_decay: 0.999999,
-            alpha: 0.1,
-            gamma: 1.,
-            prev_state: None,
-            prev_action_index: None,
-            stats: QLearningStats::new(),
+            q_table: QTable::new(config.memory_budget),
+            config: config.clone(),
+            trajectory: VecDeque::new(),
+            order: Vec::new(),
+            replay: config.replay.map(ReplayBuffer::new),
+            stats: QLearningStats::new(config),
+            rng: random::from_entropy(),
         };
-        player.stats.epsilon = player.epsilon;
+        player.stats.epsilon = player.config.epsilon;
         player
     }
 
-    fn update_q_table(&mut self, new_value: f32) {
-        // Read the q-values (we can assume they were already initialized by take_action())
-        let action_values = self
-            .q_table
-            .get_mut(self.prev_state.as_ref().unwrap())
-            .unwrap();
-        let i = self.prev_action_index.unwrap();
-        action_values.0 += 1;
-        action_values.1[i] += self.alpha * (new_value - action_values.1[i]);
+    /// Learn from the reward of the last action. `next_value` estimates the value of the state
+    /// reached, or is `None` if the episode is over
+    fn learn(&mut self, reward: f32, next_value: Option<f32>) {
+        let gamma = self.config.gamma;
+        let mut trajectory = mem::take(&mut self.trajectory);
+        match self.config.returns {
+            Returns::NStep { n } => {
+                for visit in &mut trajectory {
+                    visit.rewards += gamma.powi(visit.num_rewards as i32) * reward;
+                    visit.num_rewards += 1;
+                }
+                match next_value {
+                    Some(next_value) => {
+                        // At most one action gets its n-th reward at each step
+                        if trajectory.front().map(|visit| visit.num_rewards) >= Some(n) {
+                            let visit = trajectory.pop_front().unwrap();
+                            let new_value = visit.rewards + gamma.powi(n as i32) * next_value;
+                            self.update_q_value(&visit.state, visit.action_index, new_value, true);
+                        }
+                    }
+                    None => {
+                        for visit in trajectory.drain(..) {
+                            let (state, action_index) = (&visit.state, visit.action_index);
+                            self.update_q_value(state, action_index, visit.rewards, true);
+                        }
+                    }
+                }
+            }
+            Returns::Lambda { lambda } => {
+                // Every earlier action shares the error of the last one, weighted by its trace
+                // Nothing to learn before the first action
+                let last = match trajectory.back() {
+                    Some(last) => last,
+                    None => return,
+                };
+                let error = reward + gamma * next_value.unwrap_or(0.)
+                    - self.q_value(&last.state, last.action_index);
+                // Only the last action counts as a hit of its row
+                let mut trace = 1.;
+                for (i, visit) in trajectory.iter().rev().enumerate() {
+                    if trace == 0. {
+                        break;
+                    }
+                    let (state, action_index) = (&visit.state, visit.action_index);
+                    let value = self.q_value(state, action_index);
+                    self.update_q_value(state, action_index, value + trace * error, i == 0);
+                    trace *= gamma * lambda;
+                }
+                if next_value.is_none() {
+                    trajectory.clear();
+                }
+            }
+        }
+        self.trajectory = trajectory;
     }
-}
 
-impl<S: State, A: Action> Player<S, A> for QLearningPlayer<S> {
-    type Stats = QLearningStats;
+    /// Store the transition that followed the last action in the replay buffer, if any
+    fn remember(&mut self, reward: f32, next_state: Option<&S>) {
+        if let (Some(buffer), Some(visit)) = (&mut self.replay, self.trajectory.back()) {
+            buffer.push(Transition {
+                state: visit.state.clone(),
+                action_index: visit.action_index,
+                reward,
+                next_state: next_state.cloned(),
+            });
+            self.stats.replay_buffer_size = buffer.len() as u32;
+        }
+    }
+
+    /// Update the q-values of some past transitions, as many as the replay ratio asks for. They
+    /// always use the one-step q-learning target: the actions that followed them were chosen by an
+    /// older policy. Replays are not hits, so they don't slow down the learning rate or exploration
+    fn replay(&mut self) {
+        let mut buffer = match self.replay.take() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        for _ in 0..buffer.updates_due() {
+            if buffer.is_empty() {
+                break;
+            }
+            let index = buffer.sample(&mut self.rng);
+            let transition = buffer.get(index).clone();
+            let next_value = transition.next_state.map_or(0., |state| {
+                self.q_table
+                    .get(&state)
+                    .map_or(0., |row| max(&row.values).1)
+            });
+            let new_value = transition.reward + self.config.gamma * next_value;
+            let error = new_value - self.q_value(&transition.state, transition.action_index);
+            self.update_q_value(&transition.state, transition.action_index, new_value, false);
+            buffer.set_error(index, error);
+            self.stats.replayed_updates += 1;
+        }
+        self.replay = Some(buffer);
+    }
+
+    /// The q-value of an action, zero if the row was evicted
+    fn q_value(&self, state: &S, action_index: usize) -> f32 {
+        self.q_table
+            .get(state)
+            .map_or(0., |row| row.values[action_index])
+    }
+
+    /// Move the q-value of an action towards the new value, counting a hit of the row if
+    /// `count_hit` is set. The row was initialized when the action was chosen, but it may have been
+    /// evicted since then: the update is lost in that case
+    fn update_q_value(&mut self, state: &S, action_index: usize, new_value: f32, count_hit: bool) {
+        let row = match self.q_table.get_mut(state) {
+            Some(row) => row,
+            None => return,
+        };
+        let alpha =
+            self.config
+                .learning_rate
+                .value(self.config.alpha, self.stats.train_episodes, row.hits);
+        if count_hit {
+            row.hits += 1;
+        }
+        row.values[action_index] += alpha * (new_value - row.values[action_index]);
+    }
 
-    fn take_action(&mut self, state: S, actions: Vec<A>) -> A {
-        let game_depth = state.game_depth();
+    /// The probability of a random action in a state whose q-values were updated `visits` times
+    fn epsilon(&self, visits: u32) -> f32 {
+        self.config
+            .exploration
+            .value(self.config.epsilon, self.stats.train_episodes, visits)
+    }
+
+    /// Choose the index of an action for the given canonical state, following the epsilon-greedy
+    /// policy, and add it to the trajectory
+    fn choose_action(&mut self, state: S, num_actions: usize) -> usize {
         self.stats.total_actions += 1;
-        self.prev_state = Some(state.clone());
 
         // Ensure the q-values are initialized for this state
-        let mut inserted = false;
-        let action_values = &self
+        let row = self
             .q_table
-            .entry(state)
-            .or_insert_with(|| {
-                inserted = true;
-                (0, vec![0.; actions.len()])
-            })
-            .1;
-
-        if inserted {
-            self.stats.q_table_size += 1;
-            *self.stats.q_table_per_depth.entry(game_depth).or_default() += 1;
-        }
+            .visit(state.clone(), num_actions, &mut self.stats);
+        let epsilon =
+            self.config
+                .exploration
+                .value(self.config.epsilon, self.stats.train_episodes, row.hits);
+        let action_index = epsilon_greedy(&row.values, epsilon, &mut self.rng, &mut self.stats);
 
-        let action_index = if random::<f32>() <= self.epsilon {
-            // Take a random action
-            self.stats.random_actions += 1;
-            thread_rng().gen_range(0, actions.len())
-        } else {
-            // Take the most rewarding action
-            if action_values.iter().all(|&x| x == 0.) {
-                self.stats.dummy_actions += 1;
-            } else {
-                self.stats.learned_actions += 1;
-            }
-            max(action_values).0
-        };
+        self.trajectory.push_back(Visit {
+            state,
+            action_index,
+            rewards: 0.,
+            num_rewards: 0,
+        });
+        action_index
+    }
+}
 
-        self.prev_action_index = Some(action_index);
-        actions[action_index].clone()
+impl<S: State + StateKind + Serialize + DeserializeOwned> QLearningPlayer<S> {
+    /// Save the q-table, hyperparameters and stats, so that training can be resumed later
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: Format) -> io::Result<()> {
+        persist::save(self, &persist::kind_of::<S>(Q_LEARNING_KIND), path, format)
     }
 
-    fn step(&mut self, state: S, actions: Vec<A>, reward: f32) -> A {
-        // Read the q-values (we can assume they were already initialized by take_action())
-        let max_q_value = &self
-            .q_table
-            .get(&state)
-            .map(|(_, q_row)| max(q_row).1)
-            .unwrap_or(0.);
-        let new_value = reward + self.gamma * max_q_value;
-        self.update_q_table(new_value);
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        persist::load(&persist::kind_of::<S>(Q_LEARNING_KIND), path)
+    }
+}
+
+impl<S: State> Default for QLearningPlayer<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Canonical<A>, A: Action> Player<S, A> for QLearningPlayer<S> {
+    type Stats = QLearningStats;
+
+    fn take_action(&mut self, state: S, actions: &[A]) -> A {
+        let state = canonicalize(&state, actions, &mut self.order);
+        // A new episode starts
+        self.trajectory.clear();
+        let action_index = self.choose_action(state, actions.len());
+        actions[self.order[action_index]].clone()
+    }
+
+    fn step(&mut self, state: S, actions: &[A], reward: f32) -> A {
+        let state = canonicalize(&state, actions, &mut self.order);
         self.stats.score += reward;
-        self.take_action(state.clone(), actions)
+        self.remember(reward, Some(&state));
+
+        // Rows that were not visited yet are read as zeros
+        let action_index = match self.config.target {
+            Target::QLearning => {
+                let next_value = self
+                    .q_table
+                    .get(&state)
+                    .map_or(0., |row| max(&row.values).1);
+                self.learn(reward, Some(next_value));
+                self.choose_action(state, actions.len())
+            }
+            Target::Sarsa => {
+                // The next action must be chosen before learning, but not be learned from yet
+                let action_index = self.choose_action(state.clone(), actions.len());
+                let next = self.trajectory.pop_back().unwrap();
+                let next_value = self.q_value(&state, action_index);
+                self.learn(reward, Some(next_value));
+                self.trajectory.push_back(next);
+                action_index
+            }
+            Target::ExpectedSarsa => {
+                let next_value = self.q_table.get(&state).map_or(0., |row| {
+                    // A random action may also be the best one
+                    let epsilon = self.epsilon(row.hits);
+                    let mean = row.values.iter().sum::<f32>() / row.values.len() as f32;
+                    (1. - epsilon) * max(&row.values).1 + epsilon * mean
+                });
+                self.learn(reward, Some(next_value));
+                self.choose_action(state, actions.len())
+            }
+        };
+        self.replay();
+        actions[self.order[action_index]].clone()
     }
 
     fn end(&mut self, _state: S, reward: f32) {
-        self.update_q_table(reward);
-        self.epsilon = (self.epsilon * self.epsilon_decay).max(self.min_epsilon);
-        self.stats.epsilon = self.epsilon;
+        self.remember(reward, None);
+        self.learn(reward, None);
+        self.replay();
         self.stats.train_episodes += 1;
+        // Per-state schedules are reported as the value for an unvisited state
+        self.stats.epsilon = self.epsilon(0);
         self.stats.play_episodes += 1;
         self.stats.score += reward;
     }
 
+    fn seed(&mut self, seed: u64) {
+        self.rng = random::seeded(seed);
+    }
+
     fn reset_stats(&mut self) {
         self.stats.reset();
     }
@@ -159,7 +482,7 @@ impl<S: State, A: Action> Player<S, A> for QLearningPlayer<S> {
     }
 }
 
-impl<S: State, A: Action> LearningPlayer<S, A> for QLearningPlayer<S>
+impl<S: Canonical<A>, A: Action> LearningPlayer<S, A> for QLearningPlayer<S>
 where
     Self: Player<S, A>,
 {
@@ -169,6 +492,7 @@ where
         let mut player = QLearnedPlayer {
             q_table: self.q_table.clone(),
             stats: self.stats.clone(),
+            order: Vec::new(),
         };
         player.stats.reset();
         player
@@ -180,15 +504,15 @@ where
         if cfg!(stats_table) {
             // Load stats from q-table: hit count by (game depth, learned actions)
             let mut stats: HashMap<(u16, u8), Vec<u32>> = HashMap::new();
-            for (state, (hit_count, q_values)) in &self.q_table {
+            for (state, row) in self.q_table.iter() {
                 let learned_actions =
-                    q_values
+                    row.values
                         .iter()
                         .fold(0, |num, &q_value| if q_value == 0. { num } else { num + 1 });
                 stats
                     .entry((state.game_depth(), learned_actions))
                     .or_default()
-                    .push(*hit_count);
+                    .push(row.hits);
             }
             let mut stats: Vec<(u16, u8, u32, u32)> = stats
                 .into_iter()
@@ -216,35 +540,256 @@ where
     }
 }
 
+/// A q-learning player with two q-tables, to avoid overestimating the q-values: the action that
+/// looks best in one table is evaluated with the other one. Every update goes to one of the tables,
+/// picked at random, and actions are chosen with the sum of both
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "S: Serialize", deserialize = "S: DeserializeOwned"))]
+pub struct DoubleQLearningPlayer<S: State> {
+    q_tables: [QTable<S>; 2],
+    config: QLearningConfig,
+    #[serde(skip)]
+    prev_state: Option<S>,
+    #[serde(skip)]
+    prev_action_index: Option<usize>,
+    /// Reused by `canonicalize()` and for the sums of the q-values of both tables
+    #[serde(skip)]
+    order: Vec<usize>,
+    #[serde(skip)]
+    values: Vec<f32>,
+    /// The q-table stats count the states of both tables
+    stats: QLearningStats,
+    rng: SeededRng,
+}
+
+impl<S: State> DoubleQLearningPlayer<S> {
+    pub fn new() -> Self {
+        Self::with_config(QLearningConfig::default())
+    }
+
+    /// Each table gets half of the memory budget
+    pub fn with_config(config: QLearningConfig) -> Self {
+        let budget = config.memory_budget.map(|budget| MemoryBudget {
+            max_bytes: budget.max_bytes / 2,
+            ..budget
+        });
+        let mut player = DoubleQLearningPlayer {
+            q_tables: [QTable::new(budget), QTable::new(budget)],
+            config: config.clone(),
+            prev_state: None,
+            prev_action_index: None,
+            order: Vec::new(),
+            values: Vec::new(),
+            stats: QLearningStats::new(config),
+            rng: random::from_entropy(),
+        };
+        player.stats.epsilon = player.config.epsilon;
+        player
+    }
+
+    /// Update the q-value of the previous action in one of the tables, bootstrapping from the next
+    /// state if the game is not over
+    fn update_q_tables(&mut self, reward: f32, next_state: Option<&S>) {
+        // Nothing to update before the first action
+        let (prev_state, i) = match (&self.prev_state, self.prev_action_index) {
+            (Some(state), Some(i)) => (state, i),
+            _ => return,
+        };
+        let learner = self.rng.gen_range(0, 2);
+        let [first, second] = &mut self.q_tables;
+        let (learner_table, evaluator_table) = if learner == 0 {
+            (first, second)
+        } else {
+            (second, first)
+        };
+
+        // Missing rows are treated as zeros, like in QLearningPlayer
+        let next_value = next_state.map_or(0., |state| {
+            let best = learner_table.get(state).map_or(0, |row| max(&row.values).0);
+            evaluator_table
+                .get(state)
+                .map_or(0., |row| row.values[best])
+        });
+        let new_value = reward + self.config.gamma * next_value;
+
+        // The row was initialized by choose_action(), but may have been evicted since
+        if let Some(row) = learner_table.get_mut(prev_state) {
+            let alpha = self.config.learning_rate.value(
+                self.config.alpha,
+                self.stats.train_episodes,
+                row.hits,
+            );
+            row.hits += 1;
+            row.values[i] += alpha * (new_value - row.values[i]);
+        }
+    }
+
+    /// Choose the index of an action for the given canonical state, following the epsilon-greedy
+    /// policy on the sum of both tables
+    fn choose_action(&mut self, state: S, num_actions: usize) -> usize {
+        self.stats.total_actions += 1;
+        self.prev_state = Some(state.clone());
+
+        let [first, second] = &mut self.q_tables;
+        let first_row = first.visit(state.clone(), num_actions, &mut self.stats);
+        let second_row = second.visit(state, num_actions, &mut self.stats);
+        self.values.clear();
+        self.values.extend(
+            first_row
+                .values
+                .iter()
+                .zip(&second_row.values)
+                .map(|(a, b)| a + b),
+        );
+        let epsilon = self.config.exploration.value(
+            self.config.epsilon,
+            self.stats.train_episodes,
+            first_row.hits + second_row.hits,
+        );
+        let action_index = epsilon_greedy(&self.values, epsilon, &mut self.rng, &mut self.stats);
+
+        self.prev_action_index = Some(action_index);
+        action_index
+    }
+}
+
+impl<S: State + StateKind + Serialize + DeserializeOwned> DoubleQLearningPlayer<S> {
+    /// Save the q-tables, hyperparameters and stats, so that training can be resumed later
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: Format) -> io::Result<()> {
+        persist::save(
+            self,
+            &persist::kind_of::<S>(DOUBLE_Q_LEARNING_KIND),
+            path,
+            format,
+        )
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        persist::load(&persist::kind_of::<S>(DOUBLE_Q_LEARNING_KIND), path)
+    }
+}
+
+impl<S: State> Default for DoubleQLearningPlayer<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Canonical<A>, A: Action> Player<S, A> for DoubleQLearningPlayer<S> {
+    type Stats = QLearningStats;
+
+    fn take_action(&mut self, state: S, actions: &[A]) -> A {
+        let state = canonicalize(&state, actions, &mut self.order);
+        let action_index = self.choose_action(state, actions.len());
+        actions[self.order[action_index]].clone()
+    }
+
+    fn step(&mut self, state: S, actions: &[A], reward: f32) -> A {
+        let state = canonicalize(&state, actions, &mut self.order);
+        self.update_q_tables(reward, Some(&state));
+        self.stats.score += reward;
+        let action_index = self.choose_action(state, actions.len());
+        actions[self.order[action_index]].clone()
+    }
+
+    fn end(&mut self, _state: S, reward: f32) {
+        self.update_q_tables(reward, None);
+        self.stats.train_episodes += 1;
+        self.stats.epsilon =
+            self.config
+                .exploration
+                .value(self.config.epsilon, self.stats.train_episodes, 0);
+        self.stats.play_episodes += 1;
+        self.stats.score += reward;
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.rng = random::seeded(seed);
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
+
+    fn stats(&self) -> Option<Self::Stats> {
+        Some(self.stats.clone())
+    }
+}
+
+impl<S: Canonical<A>, A: Action> LearningPlayer<S, A> for DoubleQLearningPlayer<S>
+where
+    Self: Player<S, A>,
+{
+    type Freezed = QLearnedPlayer<S>;
+
+    /// The frozen player uses the average of both tables
+    fn freezed(&self) -> QLearnedPlayer<S> {
+        let mut player = QLearnedPlayer {
+            q_table: self.q_tables[0].average(&self.q_tables[1]),
+            stats: self.stats.clone(),
+            order: Vec::new(),
+        };
+        player.stats.reset();
+        player
+    }
+
+    fn cycle_end(&mut self) {
+        self.reset_stats();
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "S: Serialize", deserialize = "S: DeserializeOwned"))]
 pub struct QLearnedPlayer<S: State> {
-    q_table: HashMap<S, (u32, Vec<f32>)>,
+    q_table: QTable<S>,
     stats: QLearningStats,
+    /// Reused by `canonicalize()`
+    #[serde(skip)]
+    order: Vec<usize>,
+}
+
+impl<S: State> QLearnedPlayer<S> {
+    pub fn q_table(&self) -> &QTable<S> {
+        &self.q_table
+    }
 }
 
-impl<S: State, A: Action> Player<S, A> for QLearnedPlayer<S> {
+impl<S: State + StateKind + Serialize + DeserializeOwned> QLearnedPlayer<S> {
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: Format) -> io::Result<()> {
+        persist::save(self, &persist::kind_of::<S>(Q_LEARNED_KIND), path, format)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        persist::load(&persist::kind_of::<S>(Q_LEARNED_KIND), path)
+    }
+}
+
+impl<S: Canonical<A>, A: Action> Player<S, A> for QLearnedPlayer<S> {
     type Stats = QLearningStats;
 
-    fn take_action(&mut self, state: S, actions: Vec<A>) -> A {
+    fn take_action(&mut self, state: S, actions: &[A]) -> A {
         self.stats.total_actions += 1;
+        let state = canonicalize(&state, actions, &mut self.order);
+        let order = &self.order;
         match self.q_table.get(&state) {
             None => {
                 // Here we act as if the row is made of only zeros, in which case
                 // the argmax() would just return the first one
                 self.stats.dummy_actions += 1;
-                actions[0].clone()
+                actions[order[0]].clone()
             }
-            Some((_, action_values)) => {
-                if action_values.iter().all(|&x| x == 0.) {
+            Some(row) => {
+                if row.values.iter().all(|&x| x == 0.) {
                     self.stats.dummy_actions += 1;
                 } else {
                     self.stats.learned_actions += 1;
                 }
-                actions[max(&action_values).0].clone()
+                actions[order[max(&row.values).0]].clone()
             }
         }
     }
 
-    fn step(&mut self, state: S, actions: Vec<A>, reward: f32) -> A {
+    fn step(&mut self, state: S, actions: &[A], reward: f32) -> A {
         self.stats.score += reward;
         self.take_action(state, actions)
     }
@@ -263,9 +808,32 @@ impl<S: State, A: Action> Player<S, A> for QLearnedPlayer<S> {
     }
 }
 
+/// Pick the index of an action with the given q-values: a random one with probability `epsilon`,
+/// the most rewarding one otherwise
+fn epsilon_greedy(
+    values: &[f32],
+    epsilon: f32,
+    rng: &mut SeededRng,
+    stats: &mut QLearningStats,
+) -> usize {
+    if rng.gen::<f32>() <= epsilon {
+        // Take a random action
+        stats.random_actions += 1;
+        rng.gen_range(0, values.len())
+    } else {
+        // Take the most rewarding action
+        if values.iter().all(|&x| x == 0.) {
+            stats.dummy_actions += 1;
+        } else {
+            stats.learned_actions += 1;
+        }
+        max(values).0
+    }
+}
+
 /// Get the maximum value and position of a list
 /// Panics if the list is empty
-fn max(values: &Vec<f32>) -> (usize, f32) {
+fn max(values: &[f32]) -> (usize, f32) {
     let mut max_i = 0;
     let mut max_el = values[0];
     for (i, &value) in values.iter().enumerate().skip(1) {
@@ -276,3 +844,209 @@ fn max(values: &Vec<f32>) -> (usize, f32) {
     }
     (max_i, max_el)
 }
+
+/// Map a state into its canonical form, together with its valid actions.
+/// The canonical actions are sorted, so that every state in the same class lists them in the same
+/// order. `order` is filled with the index of the original action of each canonical action
+fn canonicalize<S: Canonical<A>, A: Action>(state: &S, actions: &[A], order: &mut Vec<usize>) -> S {
+    let (canonical, transform) = state.canonical();
+    order.clear();
+    order.extend(0..actions.len());
+    order.sort_unstable_by(|&i, &j| {
+        transform
+            .apply(&actions[i])
+            .cmp(&transform.apply(&actions[j]))
+    });
+    canonical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state of a tiny game, without symmetries
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+    struct TestState(u16);
+
+    #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+    struct TestAction(u8);
+
+    #[derive(Copy, Clone)]
+    struct Identity;
+
+    impl State for TestState {
+        fn game_depth(&self) -> u16 {
+            self.0
+        }
+    }
+
+    impl Action for TestAction {}
+
+    impl Transform<TestAction> for Identity {
+        fn apply(&self, action: &TestAction) -> TestAction {
+            action.clone()
+        }
+
+        fn inverse(&self) -> Self {
+            Identity
+        }
+    }
+
+    impl Canonical<TestAction> for TestState {
+        type Transform = Identity;
+
+        fn canonical(&self) -> (Self, Identity) {
+            (self.clone(), Identity)
+        }
+    }
+
+    fn actions(n: u8) -> Vec<TestAction> {
+        (0..n).map(TestAction).collect()
+    }
+
+    /// Always greedy, with a constant learning rate
+    fn greedy_config(alpha: f32) -> QLearningConfig {
+        QLearningConfig {
+            epsilon: 0.,
+            exploration: Schedule::Constant,
+            alpha,
+            ..QLearningConfig::default()
+        }
+    }
+
+    #[test]
+    fn double_q_learning_updates_both_tables() {
+        let mut player = DoubleQLearningPlayer::with_config(greedy_config(0.5));
+        player.seed(3);
+        for _ in 0..20 {
+            player.take_action(TestState(0), &actions(2));
+            player.end(TestState(1), 1.);
+        }
+        let mut hits = 0;
+        for table in &player.q_tables {
+            let row = table.get(&TestState(0)).unwrap();
+            assert!(row.hits > 0);
+            assert!(row.values[0] > 0.);
+            hits += row.hits;
+        }
+        assert_eq!(hits, 20);
+    }
+
+    #[test]
+    fn double_q_learning_acts_on_the_sum_of_both_tables() {
+        // Each table prefers another action, and the sum decides
+        for &(first, second, best) in &[([3., 0.], [-4., 2.], 1), ([3., 0.], [-2., 0.], 0)] {
+            let mut player = DoubleQLearningPlayer::with_config(greedy_config(0.5));
+            player.seed(3);
+            let mut stats = player.stats.clone();
+            for (table, values) in player.q_tables.iter_mut().zip(&[first, second]) {
+                table.visit(TestState(0), 2, &mut stats).values = values.to_vec();
+            }
+            let action = player.take_action(TestState(0), &actions(2));
+            assert_eq!(action, TestAction(best));
+        }
+    }
+
+    /// Learn from a single transition into a state whose actions are worth 3, 0 and -3, while
+    /// exploring all the time, and return the learned value of the first action and the next action
+    fn learned_target(target: Target) -> (f32, TestAction) {
+        let mut player = QLearningPlayer::with_config(QLearningConfig {
+            epsilon: 1.,
+            target,
+            ..greedy_config(1.)
+        });
+        // A seed for which the random next action is the worst one
+        player.seed(1);
+        player.take_action(TestState(0), &actions(1));
+        let mut stats = player.stats.clone();
+        player.q_table.visit(TestState(1), 3, &mut stats).values = vec![3., 0., -3.];
+        let next_action = player.step(TestState(1), &actions(3), 0.);
+        let value = player.q_table.get(&TestState(0)).unwrap().values[0];
+        (value, next_action)
+    }
+
+    #[test]
+    fn targets_differ_when_exploring() {
+        // The best next value
+        assert_eq!(learned_target(Target::QLearning).0, 3.);
+        // The value of the random next action
+        assert_eq!(learned_target(Target::Sarsa), (-3., TestAction(2)));
+        // The average next value, since every action is random
+        assert_eq!(learned_target(Target::ExpectedSarsa).0, 0.);
+    }
+
+    /// Play an episode through states 0, 1, ... with the given rewards, the last one ending it
+    fn play_episode<P: Player<TestState, TestAction>>(player: &mut P, rewards: &[f32]) {
+        player.start(TestState(0), &actions(2));
+        for (i, &reward) in rewards[..rewards.len() - 1].iter().enumerate() {
+            player.step(TestState(i as u16 + 1), &actions(2), reward);
+        }
+        player.end(TestState(rewards.len() as u16), rewards[rewards.len() - 1]);
+    }
+
+    fn q_learning(returns: Returns) -> QLearningPlayer<TestState> {
+        QLearningPlayer::with_config(QLearningConfig {
+            gamma: 0.9,
+            returns,
+            ..greedy_config(0.5)
+        })
+    }
+
+    #[test]
+    fn one_step_returns_learn_from_the_next_state() {
+        let mut player = q_learning(Returns::NStep { n: 1 });
+        play_episode(&mut player, &[1., 2.]);
+        let value = |state| player.q_table.get(&TestState(state)).unwrap().values[0];
+        // The second state was not learned yet
+        assert_eq!(value(0), 0.5 * (1. + 0.9 * 0.));
+        assert_eq!(value(1), 0.5 * 2.);
+
+        play_episode(&mut player, &[1., 2.]);
+        let value = |state| player.q_table.get(&TestState(state)).unwrap().values[0];
+        assert_eq!(value(0), 0.5 + 0.5 * (1. + 0.9 * 1. - 0.5));
+        assert_eq!(value(1), 1. + 0.5 * (2. - 1.));
+    }
+
+    #[test]
+    fn lambda_returns_without_traces_are_one_step_returns() {
+        let mut one_step = q_learning(Returns::NStep { n: 1 });
+        let mut lambda = q_learning(Returns::Lambda { lambda: 0. });
+        for episode in 0..10 {
+            let rewards: Vec<f32> = (0..4).map(|i| ((episode * i) % 3) as f32 - 1.).collect();
+            play_episode(&mut one_step, &rewards);
+            play_episode(&mut lambda, &rewards);
+        }
+        assert_eq!(one_step.q_table.len(), lambda.q_table.len());
+        for (state, row) in one_step.q_table.iter() {
+            let lambda_row = lambda.q_table.get(state).unwrap();
+            assert_eq!(row.hits, lambda_row.hits);
+            for (value, lambda_value) in row.values.iter().zip(&lambda_row.values) {
+                assert!((value - lambda_value).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn only_learned_actions_count_as_hits() {
+        for &returns in &[Returns::NStep { n: 3 }, Returns::Lambda { lambda: 0.9 }] {
+            let mut player = q_learning(returns);
+            play_episode(&mut player, &[0., 0., 0., 1.]);
+            for (_, row) in player.q_table.iter() {
+                assert_eq!(row.hits, 1, "{:?}", returns);
+            }
+            assert_eq!(player.q_table.len(), 4);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn n_step_returns_need_a_step() {
+        q_learning(Returns::NStep { n: 0 });
+    }
+
+    #[test]
+    #[should_panic]
+    fn lambda_cannot_exceed_1() {
+        q_learning(Returns::Lambda { lambda: 1.5 });
+    }
+}
@@ -0,0 +1,202 @@
+//! Build players from short textual descriptions, so that any kind of player can be picked at
+//! runtime (from the command line, for example)
+//!
+//! A spec is one of `dummy`, `random`, `mcts[:ITERATIONS[:ROLLOUT[:EXPLORATION]]]`,
+//! `solver[:MAX_NODES]` or the path to a saved q-learning player. The rollout of MCTS is `random` or
+//! `greedy`, and empty arguments keep their default (as in `mcts::greedy`).
+
+use crate::board::Action;
+use crate::environment::{Environment, State};
+use crate::mcts::{Budget, MctsPlayer, Rollout};
+use crate::persist::{self, StateKind};
+use crate::player::*;
+use crate::rules::Rules;
+use crate::simple_players::{DummyPlayer, RandomPlayer};
+use crate::solver::{Solver, SolverPlayer};
+use crate::split::SplitState;
+use crate::traits::*;
+use std::io;
+
+const DEFAULT_MCTS_ITERATIONS: u32 = 1_000;
+const DEFAULT_SOLVER_NODES: u64 = 1_000_000;
+
+/// One of the players available for Quarto
+pub enum AnyPlayer {
+    Dummy(DummyPlayer),
+    Random(RandomPlayer),
+    Mcts(MctsPlayer<Environment>),
+    Solver(SolverPlayer),
+    Learned(Box<QLearnedPlayer<State>>),
+}
+
+impl AnyPlayer {
+    /// Create the player described by the spec. Saved players are loaded from disk: both frozen
+    /// players and players saved during training are accepted
+    pub fn from_spec(spec: &str) -> io::Result<Self> {
+        let mut parts = spec.split(':');
+        let name = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+        let arg = |i: usize| args.get(i).copied().filter(|arg| !arg.is_empty());
+        let player = match (name, args.len()) {
+            ("dummy", 0) => AnyPlayer::Dummy(DummyPlayer::new()),
+            ("random", 0) => AnyPlayer::Random(RandomPlayer::new()),
+            ("mcts", 0..=3) => {
+                let iterations = parse_arg(spec, arg(0), DEFAULT_MCTS_ITERATIONS)?;
+                let rollout = match arg(1) {
+                    None | Some("random") => Rollout::Random,
+                    Some("greedy") => Rollout::Greedy,
+                    Some(_) => return Err(invalid_spec(spec)),
+                };
+                let mut player = MctsPlayer::new(Budget::Iterations(iterations)).rollout(rollout);
+                if let Some(exploration) = arg(2) {
+                    let exploration: f32 = parse_arg(spec, Some(exploration), 0.)?;
+                    if !(exploration >= 0. && exploration.is_finite()) {
+                        return Err(invalid_spec(spec));
+                    }
+                    player = player.exploration(exploration);
+                }
+                AnyPlayer::Mcts(player)
+            }
+            ("solver", 0..=1) => {
+                let max_nodes = parse_arg(spec, arg(0), DEFAULT_SOLVER_NODES)?;
+                AnyPlayer::Solver(SolverPlayer::new(Solver::with_node_limit(max_nodes)))
+            }
+            ("dummy", _) | ("random", _) | ("mcts", _) | ("solver", _) => {
+                return Err(invalid_spec(spec))
+            }
+            _ => AnyPlayer::Learned(Box::new(load_learned(spec)?)),
+        };
+        Ok(player)
+    }
+
+    /// The rules a saved player was trained with, which are part of the states it learned. Other
+    /// players can play with any rules
+    pub fn rules(&self) -> Option<Rules> {
+        match self {
+            AnyPlayer::Learned(player) => {
+                let mut states = player.q_table().iter();
+                states.next().map(|(state, _)| state.rules())
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(spec: &str, arg: Option<&str>, default: T) -> io::Result<T> {
+    match arg {
+        None => Ok(default),
+        Some(arg) => arg.parse().map_err(|_| invalid_spec(spec)),
+    }
+}
+
+fn invalid_spec(spec: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid player spec: {}", spec),
+    )
+}
+
+/// Load a saved player of any kind, as told by the header of the file. Players trained with split
+/// turns are rejected, since they cannot play the standard game
+fn load_learned(path: &str) -> io::Result<QLearnedPlayer<State>> {
+    let kind = persist::saved_kind(path)?;
+    if kind == persist::kind_of::<State>(Q_LEARNING_KIND) {
+        let player = QLearningPlayer::<State>::load(path)?;
+        Ok(LearningPlayer::<State, Action>::freezed(&player))
+    } else if kind == persist::kind_of::<State>(DOUBLE_Q_LEARNING_KIND) {
+        let player = DoubleQLearningPlayer::<State>::load(path)?;
+        Ok(LearningPlayer::<State, Action>::freezed(&player))
+    } else if kind.ends_with(&format!("/{}", SplitState::NAME)) {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} was trained with split turns, which are not supported here",
+                kind
+            ),
+        ))
+    } else {
+        QLearnedPlayer::load(path)
+    }
+}
+
+impl Player<State, Action> for AnyPlayer {
+    /// The stats of the wrapped player, in JSON
+    type Stats = serde_json::Value;
+
+    fn take_action(&mut self, state: State, actions: &[Action]) -> Action {
+        match self {
+            AnyPlayer::Dummy(player) => player.take_action(state, actions),
+            AnyPlayer::Random(player) => player.take_action(state, actions),
+            AnyPlayer::Mcts(player) => player.take_action(state, actions),
+            AnyPlayer::Solver(player) => player.take_action(state, actions),
+            AnyPlayer::Learned(player) => player.take_action(state, actions),
+        }
+    }
+
+    fn start(&mut self, state: State, actions: &[Action]) -> Action {
+        match self {
+            AnyPlayer::Dummy(player) => player.start(state, actions),
+            AnyPlayer::Random(player) => player.start(state, actions),
+            AnyPlayer::Mcts(player) => player.start(state, actions),
+            AnyPlayer::Solver(player) => player.start(state, actions),
+            AnyPlayer::Learned(player) => player.start(state, actions),
+        }
+    }
+
+    fn step(&mut self, state: State, actions: &[Action], reward: f32) -> Action {
+        match self {
+            AnyPlayer::Dummy(player) => player.step(state, actions, reward),
+            AnyPlayer::Random(player) => player.step(state, actions, reward),
+            AnyPlayer::Mcts(player) => player.step(state, actions, reward),
+            AnyPlayer::Solver(player) => player.step(state, actions, reward),
+            AnyPlayer::Learned(player) => player.step(state, actions, reward),
+        }
+    }
+
+    fn end(&mut self, state: State, reward: f32) {
+        match self {
+            AnyPlayer::Dummy(player) => Player::<State, Action>::end(player, state, reward),
+            AnyPlayer::Random(player) => Player::<State, Action>::end(player, state, reward),
+            AnyPlayer::Mcts(player) => player.end(state, reward),
+            AnyPlayer::Solver(player) => player.end(state, reward),
+            AnyPlayer::Learned(player) => {
+                Player::<State, Action>::end(player.as_mut(), state, reward)
+            }
+        }
+    }
+
+    fn seed(&mut self, seed: u64) {
+        match self {
+            AnyPlayer::Dummy(player) => Player::<State, Action>::seed(player, seed),
+            AnyPlayer::Random(player) => Player::<State, Action>::seed(player, seed),
+            AnyPlayer::Mcts(player) => player.seed(seed),
+            AnyPlayer::Solver(player) => player.seed(seed),
+            AnyPlayer::Learned(player) => Player::<State, Action>::seed(player.as_mut(), seed),
+        }
+    }
+
+    fn reset_stats(&mut self) {
+        match self {
+            AnyPlayer::Dummy(player) => Player::<State, Action>::reset_stats(player),
+            AnyPlayer::Random(player) => Player::<State, Action>::reset_stats(player),
+            AnyPlayer::Mcts(player) => player.reset_stats(),
+            AnyPlayer::Solver(player) => player.reset_stats(),
+            AnyPlayer::Learned(player) => Player::<State, Action>::reset_stats(player.as_mut()),
+        }
+    }
+
+    fn stats(&self) -> Option<serde_json::Value> {
+        let stats = match self {
+            AnyPlayer::Dummy(player) => to_json(Player::<State, Action>::stats(player)),
+            AnyPlayer::Random(player) => to_json(Player::<State, Action>::stats(player)),
+            AnyPlayer::Mcts(player) => to_json(player.stats()),
+            AnyPlayer::Solver(player) => to_json(player.stats()),
+            AnyPlayer::Learned(player) => to_json(Player::<State, Action>::stats(player.as_ref())),
+        };
+        stats.filter(|stats| !stats.is_null())
+    }
+}
+
+fn to_json<T: serde::Serialize>(stats: Option<T>) -> Option<serde_json::Value> {
+    stats.and_then(|stats| serde_json::to_value(stats).ok())
+}
@@ -0,0 +1,310 @@
+use crate::persist;
+use crate::player::QLearningStats;
+use crate::traits::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::mem;
+
+/// The q-values of a state, with some bookkeeping used for stats and eviction
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QRow {
+    /// How many times the q-values were updated for an action taken in this state (not counting
+    /// the updates of eligibility traces and replays)
+    pub hits: u32,
+    /// The value of the table clock when this row was last visited
+    pub last_visit: u64,
+    pub values: Vec<f32>,
+}
+
+/// Which entries are removed first when the table goes over its memory budget
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Eviction {
+    LeastRecentlyVisited,
+    LowestHitCount,
+    DeepestFirst,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MemoryBudget {
+    /// The estimated size of the table will be kept under this value
+    pub max_bytes: usize,
+    pub eviction: Eviction,
+    /// When the budget is exceeded, entries are evicted until the table shrinks to this fraction
+    /// of the budget. Evicting in batches amortizes the cost of sorting the entries
+    pub shrink_to: f32,
+}
+
+impl MemoryBudget {
+    /// Panics if `shrink_to` is not in (0, 1]
+    pub fn new(max_bytes: usize, eviction: Eviction, shrink_to: f32) -> Self {
+        let budget = MemoryBudget {
+            max_bytes,
+            eviction,
+            shrink_to,
+        };
+        budget.assert_valid();
+        budget
+    }
+
+    fn assert_valid(&self) {
+        assert!(
+            self.shrink_to > 0. && self.shrink_to <= 1.,
+            "the table must shrink to a fraction of its budget in (0, 1], not {}",
+            self.shrink_to
+        );
+    }
+}
+
+/// A map from state to q-values that can be bounded in memory
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "S: Serialize", deserialize = "S: DeserializeOwned"))]
+pub struct QTable<S: State> {
+    #[serde(with = "persist::entries")]
+    rows: HashMap<S, QRow>,
+    clock: u64,
+    bytes: usize,
+    budget: Option<MemoryBudget>,
+}
+
+impl<S: State> QTable<S> {
+    /// Panics if the budget is invalid (see `MemoryBudget::new()`)
+    pub fn new(budget: Option<MemoryBudget>) -> Self {
+        if let Some(budget) = &budget {
+            budget.assert_valid();
+        }
+        QTable {
+            rows: HashMap::new(),
+            clock: 0,
+            bytes: 0,
+            budget,
+        }
+    }
+
+    pub fn get(&self, state: &S) -> Option<&QRow> {
+        self.rows.get(state)
+    }
+
+    pub fn get_mut(&mut self, state: &S) -> Option<&mut QRow> {
+        self.rows.get_mut(state)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&S, &QRow)> {
+        self.rows.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The estimated memory used by the table, in bytes
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Return the row for the given state, initializing it with zeros if needed.
+    /// Inserting may evict other rows to stay within the memory budget. The stats are updated
+    /// to reflect both
+    pub fn visit(&mut self, state: S, num_actions: usize, stats: &mut QLearningStats) -> &mut QRow {
+        self.clock += 1;
+        if !self.rows.contains_key(&state) {
+            let bytes = row_bytes::<S>(num_actions);
+            if let Some(budget) = self.budget {
+                if self.bytes + bytes > budget.max_bytes {
+                    self.evict(budget, bytes, stats);
+                }
+            }
+            self.bytes += bytes;
+            stats.q_table_size += 1;
+            *stats
+                .q_table_per_depth
+                .entry(state.game_depth())
+                .or_default() += 1;
+        }
+
+        let clock = self.clock;
+        let row = self.rows.entry(state).or_insert_with(|| QRow {
+            hits: 0,
+            last_visit: 0,
+            values: vec![0.; num_actions],
+        });
+        row.last_visit = clock;
+        row
+    }
+
+    /// A table with the average q-values of both tables. Rows missing from one of them (because
+    /// they were evicted) are copied from the other
+    pub fn average(&self, other: &QTable<S>) -> QTable<S> {
+        let mut table = self.clone();
+        for (state, row) in &other.rows {
+            match table.rows.get_mut(state) {
+                Some(own_row) => {
+                    for (own_value, value) in own_row.values.iter_mut().zip(&row.values) {
+                        *own_value = (*own_value + value) / 2.;
+                    }
+                    own_row.hits += row.hits;
+                    own_row.last_visit = own_row.last_visit.max(row.last_visit);
+                }
+                None => {
+                    table.bytes += row_bytes::<S>(row.values.len());
+                    table.rows.insert(state.clone(), row.clone());
+                }
+            }
+        }
+        table.clock = table.clock.max(other.clock);
+        table
+    }
+
+    /// Remove entries until the table is back to the target size, counting a new row of
+    /// `new_bytes` that is about to be inserted
+    fn evict(&mut self, budget: MemoryBudget, new_bytes: usize, stats: &mut QLearningStats) {
+        let target = (budget.max_bytes as f32 * budget.shrink_to) as usize;
+        let bytes = self.bytes + new_bytes;
+        if self.rows.is_empty() || bytes <= target {
+            return;
+        }
+        let avg_bytes = self.bytes / self.rows.len();
+        let num_evicted = (bytes - target)
+            .div_ceil(avg_bytes.max(1))
+            .min(self.rows.len());
+
+        // Sort the keys so that the ones to evict come first
+        let mut ranked: Vec<((u64, u64), &S)> = self
+            .rows
+            .iter()
+            .map(|(state, row)| {
+                let rank = match budget.eviction {
+                    Eviction::LeastRecentlyVisited => (row.last_visit, 0),
+                    Eviction::LowestHitCount => (row.hits as u64, row.last_visit),
+                    Eviction::DeepestFirst => {
+                        (u16::MAX as u64 - state.game_depth() as u64, row.last_visit)
+                    }
+                };
+                (rank, state)
+            })
+            .collect();
+        if num_evicted < ranked.len() {
+            ranked.select_nth_unstable_by_key(num_evicted, |(rank, _)| *rank);
+        }
+        let evicted: Vec<S> = ranked[..num_evicted]
+            .iter()
+            .map(|(_, state)| (*state).clone())
+            .collect();
+
+        for state in evicted {
+            let row = self.rows.remove(&state).unwrap();
+            let depth = state.game_depth();
+            self.bytes -= row_bytes::<S>(row.values.len());
+            stats.q_table_size -= 1;
+            stats.evicted += 1;
+            *stats.q_table_per_depth.entry(depth).or_default() -= 1;
+            *stats.evicted_per_depth.entry(depth).or_default() += 1;
+        }
+    }
+}
+
+/// Estimate the memory used by a row: the entry stored in the hash map (plus its control byte and
+/// the spare capacity of the map) and the heap allocated q-values
+fn row_bytes<S>(num_actions: usize) -> usize {
+    (mem::size_of::<(S, QRow)>() + 1) * 8 / 7 + num_actions * mem::size_of::<f32>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::QLearningConfig;
+
+    /// A state with a given game depth
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+    struct TestState {
+        depth: u16,
+        id: u32,
+    }
+
+    impl State for TestState {
+        fn game_depth(&self) -> u16 {
+            self.depth
+        }
+    }
+
+    fn state(depth: u16, id: u32) -> TestState {
+        TestState { depth, id }
+    }
+
+    /// A budget of `rows` rows of `NUM_ACTIONS` q-values
+    fn budget(rows: usize, eviction: Eviction, shrink_to: f32) -> MemoryBudget {
+        MemoryBudget::new(
+            rows * row_bytes::<TestState>(NUM_ACTIONS),
+            eviction,
+            shrink_to,
+        )
+    }
+
+    const NUM_ACTIONS: usize = 4;
+
+    /// Fill a table with room for 4 rows, then insert a fifth one and return the rows that were
+    /// kept among the first four
+    fn kept_rows(eviction: Eviction) -> Vec<u32> {
+        let mut stats = QLearningStats::new(QLearningConfig::default());
+        let mut table = QTable::new(Some(budget(4, eviction, 0.5)));
+        // Visited in this order, with these depths and hit counts
+        for &(id, depth, hits) in &[(0, 3, 0), (1, 1, 0), (2, 2, 4), (3, 4, 0)] {
+            table.visit(state(depth, id), NUM_ACTIONS, &mut stats).hits = hits;
+        }
+        table.visit(state(0, 4), NUM_ACTIONS, &mut stats);
+
+        assert_eq!(stats.evicted, 3);
+        assert_eq!(stats.q_table_size, table.len() as u32);
+        let mut kept: Vec<u32> = table.iter().map(|(state, _)| state.id).collect();
+        kept.sort_unstable();
+        assert_eq!(kept.pop(), Some(4));
+        kept
+    }
+
+    #[test]
+    fn least_recently_visited_rows_are_evicted_first() {
+        assert_eq!(kept_rows(Eviction::LeastRecentlyVisited), [3]);
+    }
+
+    #[test]
+    fn least_hit_rows_are_evicted_first() {
+        assert_eq!(kept_rows(Eviction::LowestHitCount), [2]);
+    }
+
+    #[test]
+    fn deepest_rows_are_evicted_first() {
+        assert_eq!(kept_rows(Eviction::DeepestFirst), [1]);
+    }
+
+    #[test]
+    fn tables_stay_under_their_budget() {
+        for &shrink_to in &[0.5, 0.9, 1.] {
+            let budget = budget(10, Eviction::LeastRecentlyVisited, shrink_to);
+            let mut stats = QLearningStats::new(QLearningConfig::default());
+            let mut table = QTable::new(Some(budget));
+            for id in 0..100 {
+                table.visit(state((id % 16) as u16, id), NUM_ACTIONS, &mut stats);
+                assert!(table.bytes() <= budget.max_bytes);
+            }
+            assert!(table.len() >= 5);
+            assert_eq!(stats.q_table_size, table.len() as u32);
+            assert_eq!(stats.evicted, 100 - table.len() as u32);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn tables_cannot_shrink_over_their_budget() {
+        budget(10, Eviction::LeastRecentlyVisited, 1.5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn tables_cannot_shrink_to_nan() {
+        budget(10, Eviction::LeastRecentlyVisited, f32::NAN);
+    }
+}
@@ -0,0 +1,18 @@
+//! Seedable random number generation
+//!
+//! Every stochastic component owns one of these generators instead of using `thread_rng()`, so that
+//! a whole run can be reproduced from a single seed.
+
+use rand::prelude::*;
+use rand_pcg::Pcg64Mcg;
+
+pub type SeededRng = Pcg64Mcg;
+
+pub fn seeded(seed: u64) -> SeededRng {
+    SeededRng::seed_from_u64(seed)
+}
+
+/// A generator seeded from the operating system, for components that were not explicitly seeded
+pub fn from_entropy() -> SeededRng {
+    SeededRng::from_entropy()
+}
@@ -0,0 +1,234 @@
+//! Game records: a few tags describing the game, the list of actions and the result
+//!
+//! Records are written in a format inspired by PGN, with one action per line:
+//!
+//! ```text
+//! [Player1 "mcts"]
+//! [Player2 "random"]
+//! [Rules "squares"]
+//! [Opening "WTRF"]
+//!
+//! 1. c3 WTRF
+//! 2. a1 BSQH
+//! ...
+//! 1-0
+//! ```
+//!
+//! The result is from the point of view of the player that took the first action: `1-0`, `0-1`,
+//! `1/2-1/2` or `*` for an unfinished game. The `Rules` and `Opening` tags, when present, give the
+//! rules and the first piece the game was played with. Several records can follow each other in
+//! the same file.
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GameResult {
+    FirstPlayerWins,
+    SecondPlayerWins,
+    Draw,
+}
+
+impl GameResult {
+    /// The result of a game where the first player got the given score
+    pub fn from_score(score: f32) -> Self {
+        if score > 0. {
+            GameResult::FirstPlayerWins
+        } else if score < 0. {
+            GameResult::SecondPlayerWins
+        } else {
+            GameResult::Draw
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct GameRecord<A> {
+    /// Pairs of name and value, in the order they are written
+    pub tags: Vec<(String, String)>,
+    pub actions: Vec<A>,
+    /// `None` while the game is not finished
+    pub result: Option<GameResult>,
+}
+
+impl<A> GameRecord<A> {
+    pub fn new() -> Self {
+        GameRecord {
+            tags: Vec::new(),
+            actions: Vec::new(),
+            result: None,
+        }
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Set the value of a tag, replacing the previous one
+    pub fn set_tag(&mut self, name: &str, value: &str) {
+        match self.tags.iter_mut().find(|(tag, _)| tag == name) {
+            Some((_, old_value)) => *old_value = value.to_owned(),
+            None => self.tags.push((name.to_owned(), value.to_owned())),
+        }
+    }
+}
+
+impl<A> Default for GameRecord<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: fmt::Display> fmt::Display for GameRecord<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in &self.tags {
+            writeln!(f, "[{} \"{}\"]", name, value)?;
+        }
+        if !self.tags.is_empty() {
+            writeln!(f)?;
+        }
+        for (i, action) in self.actions.iter().enumerate() {
+            writeln!(f, "{}. {}", i + 1, action)?;
+        }
+        let result = match self.result {
+            Some(GameResult::FirstPlayerWins) => "1-0",
+            Some(GameResult::SecondPlayerWins) => "0-1",
+            Some(GameResult::Draw) => "1/2-1/2",
+            None => "*",
+        };
+        writeln!(f, "{}", result)
+    }
+}
+
+/// The error returned when a record cannot be parsed, with the line (starting at 1) where the
+/// problem was found
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Parse all records in the text
+pub fn parse_records<A>(text: &str) -> Result<Vec<GameRecord<A>>, ParseError>
+where
+    A: FromStr,
+    A::Err: fmt::Display,
+{
+    let mut records = Vec::new();
+    let mut record = GameRecord::new();
+    let mut empty = true;
+    for (i, line) in text.lines().enumerate() {
+        let error = |message: String| ParseError {
+            line: i + 1,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        empty = false;
+
+        if let Some(tag) = line.strip_prefix('[') {
+            if !record.actions.is_empty() {
+                return Err(error("tags must come before the actions".to_owned()));
+            }
+            let (name, value) = parse_tag(tag).ok_or_else(|| error("invalid tag".to_owned()))?;
+            record.tags.push((name.to_owned(), value.to_owned()));
+            continue;
+        }
+
+        let result = match line {
+            "1-0" => Some(Some(GameResult::FirstPlayerWins)),
+            "0-1" => Some(Some(GameResult::SecondPlayerWins)),
+            "1/2-1/2" => Some(Some(GameResult::Draw)),
+            "*" => Some(None),
+            _ => None,
+        };
+        match result {
+            Some(result) => {
+                record.result = result;
+                records.push(record);
+                record = GameRecord::new();
+                empty = true;
+            }
+            None => {
+                // Skip the move number
+                let action = match line.split_once(". ") {
+                    Some((number, action)) if number.chars().all(|c| c.is_ascii_digit()) => action,
+                    _ => line,
+                };
+                let action = action.trim().parse().map_err(|e| error(format!("{}", e)))?;
+                record.actions.push(action);
+            }
+        }
+    }
+    if !empty {
+        return Err(ParseError {
+            line: text.lines().count(),
+            message: "missing the result of the game".to_owned(),
+        });
+    }
+    Ok(records)
+}
+
+/// Split `Name "value"]` into its name and value
+fn parse_tag(tag: &str) -> Option<(&str, &str)> {
+    let tag = tag.strip_suffix(']')?;
+    let (name, value) = tag.split_once(' ')?;
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((name, value))
+}
+
+/// Parse a single record
+impl<A> FromStr for GameRecord<A>
+where
+    A: FromStr,
+    A::Err: fmt::Display,
+{
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let mut records = parse_records(s)?;
+        if records.len() != 1 {
+            return Err(ParseError {
+                line: s.lines().count(),
+                message: format!("expected a single record, found {}", records.len()),
+            });
+        }
+        Ok(records.pop().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Action;
+
+    #[test]
+    fn records_round_trip_through_text() {
+        let mut record = GameRecord::new();
+        record.set_tag("Player1", "mcts");
+        record.set_tag("Player2", "random");
+        record.actions = (0..10).map(|i| Action::from(23 * i)).collect();
+        record.result = Some(GameResult::SecondPlayerWins);
+        let text = record.to_string();
+        assert_eq!(text.parse::<GameRecord<Action>>().unwrap(), record);
+
+        let mut unfinished = GameRecord::<Action>::new();
+        unfinished.actions.push(Action::from(0));
+        let text = format!("{}\n{}", record, unfinished);
+        assert_eq!(parse_records(&text).unwrap(), vec![record, unfinished]);
+    }
+}
@@ -0,0 +1,232 @@
+//! Experience replay: a bounded buffer of past transitions, sampled to update their q-values again
+//!
+//! Transitions are either sampled uniformly or proportionally to the error of their last update, so
+//! that the surprising ones are replayed more often.
+
+use crate::random::SeededRng;
+use rand::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// The priority of a transition whose last update had no error, so that it can still be replayed
+const MIN_PRIORITY: f32 = 0.01;
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ReplayConfig {
+    /// The maximum number of transitions kept. The oldest ones are replaced first
+    pub capacity: usize,
+    /// The number of transitions replayed after every action. Can be fractional
+    pub ratio: f32,
+    pub sampling: Sampling,
+}
+
+/// How the transitions to replay are picked
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Sampling {
+    Uniform,
+    /// Proportionally to the error of their last update. New transitions get the highest priority
+    /// seen so far, so that they are replayed soon.
+    /// There is no importance-sampling correction, so the q-values are biased towards the
+    /// transitions with large errors
+    Prioritized,
+}
+
+/// An action taken in a canonical state and what followed it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transition<S> {
+    pub state: S,
+    pub action_index: usize,
+    pub reward: f32,
+    /// The canonical state in which the player acted next, or `None` if the episode was over
+    pub next_state: Option<S>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "S: Serialize", deserialize = "S: DeserializeOwned"))]
+pub struct ReplayBuffer<S> {
+    config: ReplayConfig,
+    transitions: Vec<Transition<S>>,
+    /// The index of the next transition to replace, once the buffer is full
+    oldest: usize,
+    /// A sum tree of the priorities (prioritized sampling only): the priority of transition `i` is
+    /// at `capacity + i` and every other node `n` holds the sum of the nodes `2n` and `2n + 1`
+    priorities: Vec<f32>,
+    max_priority: f32,
+    /// The fraction of a replayed update carried over to the next action
+    credit: f32,
+}
+
+impl<S> ReplayBuffer<S> {
+    /// Panics if the capacity is zero
+    pub fn new(config: ReplayConfig) -> Self {
+        assert!(config.capacity > 0, "the replay buffer needs a capacity");
+        let priorities = match config.sampling {
+            Sampling::Uniform => Vec::new(),
+            Sampling::Prioritized => vec![0.; 2 * config.capacity],
+        };
+        ReplayBuffer {
+            config,
+            transitions: Vec::with_capacity(config.capacity),
+            oldest: 0,
+            priorities,
+            max_priority: MIN_PRIORITY,
+            credit: 0.,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transitions.is_empty()
+    }
+
+    /// Add a transition, replacing the oldest one if the buffer is full
+    pub fn push(&mut self, transition: Transition<S>) {
+        let index = if self.transitions.len() < self.config.capacity {
+            self.transitions.push(transition);
+            self.transitions.len() - 1
+        } else {
+            let index = self.oldest;
+            self.transitions[index] = transition;
+            self.oldest = (index + 1) % self.config.capacity;
+            index
+        };
+        self.set_priority(index, self.max_priority);
+    }
+
+    pub fn get(&self, index: usize) -> &Transition<S> {
+        &self.transitions[index]
+    }
+
+    /// The number of transitions to replay after an action, following the replay ratio
+    pub fn updates_due(&mut self) -> u32 {
+        self.credit += self.config.ratio;
+        let updates = self.credit.floor();
+        self.credit -= updates;
+        updates as u32
+    }
+
+    /// Pick the index of a transition to replay. Panics if the buffer is empty
+    pub fn sample(&self, rng: &mut SeededRng) -> usize {
+        match self.config.sampling {
+            Sampling::Uniform => rng.gen_range(0, self.transitions.len()),
+            Sampling::Prioritized => {
+                let capacity = self.config.capacity;
+                let mut value = rng.gen::<f32>() * self.priorities[1];
+                let mut node = 1;
+                while node < capacity {
+                    let left = 2 * node;
+                    if value < self.priorities[left] {
+                        node = left;
+                    } else {
+                        value -= self.priorities[left];
+                        node = left + 1;
+                    }
+                }
+                // Rounding errors could lead to an empty slot
+                (node - capacity).min(self.transitions.len() - 1)
+            }
+        }
+    }
+
+    /// Record the error of the last update of a transition, to prioritize it
+    pub fn set_error(&mut self, index: usize, error: f32) {
+        let priority = error.abs() + MIN_PRIORITY;
+        self.max_priority = self.max_priority.max(priority);
+        self.set_priority(index, priority);
+    }
+
+    fn set_priority(&mut self, index: usize, priority: f32) {
+        if self.priorities.is_empty() {
+            return;
+        }
+        let mut node = self.config.capacity + index;
+        self.priorities[node] = priority;
+        while node > 1 {
+            node /= 2;
+            self.priorities[node] = self.priorities[2 * node] + self.priorities[2 * node + 1];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+
+    fn prioritized(capacity: usize) -> ReplayBuffer<u8> {
+        ReplayBuffer::new(ReplayConfig {
+            capacity,
+            ratio: 1.,
+            sampling: Sampling::Prioritized,
+        })
+    }
+
+    fn transition(state: u8) -> Transition<u8> {
+        Transition {
+            state,
+            action_index: 0,
+            reward: 0.,
+            next_state: None,
+        }
+    }
+
+    /// Sample many times and return the frequency of each transition
+    fn frequencies(buffer: &ReplayBuffer<u8>) -> Vec<f32> {
+        let mut rng = random::seeded(5);
+        let mut counts = vec![0; buffer.len()];
+        for _ in 0..20_000 {
+            counts[buffer.sample(&mut rng)] += 1;
+        }
+        counts.iter().map(|&count| count as f32 / 20_000.).collect()
+    }
+
+    fn assert_close(frequencies: &[f32], expected: &[f32]) {
+        for (frequency, expected) in frequencies.iter().zip(expected) {
+            assert!(
+                (frequency - expected).abs() < 0.02,
+                "{:?} != {:?}",
+                frequencies,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn samples_proportionally_to_priorities() {
+        // Power of two or not, the tree is complete
+        for &capacity in &[3, 4] {
+            let mut buffer = prioritized(capacity);
+            for (state, &error) in [0.99, 2.99, 5.99].iter().enumerate() {
+                buffer.push(transition(state as u8));
+                buffer.set_error(state, error);
+            }
+            assert_close(&frequencies(&buffer), &[0.1, 0.3, 0.6]);
+        }
+    }
+
+    #[test]
+    fn overwritten_transitions_get_a_new_priority() {
+        let mut buffer = prioritized(3);
+        for (state, &error) in [0.99, 2.99, 5.99].iter().enumerate() {
+            buffer.push(transition(state as u8));
+            buffer.set_error(state, error);
+        }
+        // Replaces the first transition, with the highest priority seen so far
+        buffer.push(transition(3));
+        assert_eq!(buffer.get(0).state, 3);
+        assert_close(&frequencies(&buffer), &[0.4, 0.2, 0.4]);
+
+        buffer.set_error(0, 3.99);
+        assert_close(&frequencies(&buffer), &[4. / 13., 3. / 13., 6. / 13.]);
+        assert!((buffer.priorities[1] - 13.).abs() < 1e-4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn needs_a_capacity() {
+        prioritized(0);
+    }
+}
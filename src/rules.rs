@@ -0,0 +1,133 @@
+//! The rules of the game and its variants
+//!
+//! In the standard game, four pieces sharing an attribute win when they fill a row, a column or a
+//! diagonal. The advanced variant also counts the four pieces of any 2x2 square, optionally
+//! including the squares that wrap around the edges of the board.
+//!
+//! With the calling rule, a Quarto must also be claimed to win. Without it, wins are awarded
+//! automatically, as if every player was forced to claim them.
+
+use crate::board::ParseError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The groups of four cells that can win, as bit masks: the rows, columns and diagonals, then the
+/// 2x2 squares and finally the squares that wrap around the edges
+const WINNING_LINES: [u16; 26] = [
+    0x000F, 0x00F0, 0x0F00, 0xF000, 0x1111, 0x2222, 0x4444, 0x8888, 0x8421, 0x1248, // lines
+    0x0033, 0x0066, 0x00CC, 0x0330, 0x0660, 0x0CC0, 0x3300, 0x6600, 0xCC00, // squares
+    0x0099, 0x0990, 0x9900, 0x3003, 0x6006, 0xC00C, 0x9009, // wrap-around squares
+];
+
+/// The number of winning lines of each variant, see `Rules::variant()`
+const VARIANT_LINES: [usize; VARIANTS] = [10, 19, 26];
+
+/// The number of variants with different winning lines
+pub(crate) const VARIANTS: usize = 3;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rules {
+    /// Four pieces in a 2x2 square also win
+    pub squares: bool,
+    /// Squares that wrap around the edges of the board also win. Only used with `squares`
+    pub wrap_around: bool,
+    /// A Quarto only wins when claimed, by the player that made it or by the opponent on the next
+    /// turn. Claiming is a separate decision, so this is only supported by
+    /// `split::SplitEnvironment`
+    pub calling: bool,
+}
+
+impl Rules {
+    pub fn standard() -> Self {
+        Rules::default()
+    }
+
+    /// The advanced variant, where the 2x2 squares also win
+    pub fn advanced() -> Self {
+        Rules {
+            squares: true,
+            ..Rules::default()
+        }
+    }
+
+    /// The groups of four cells that win when their pieces share an attribute, as bit masks
+    pub(crate) fn lines(&self) -> &'static [u16] {
+        variant_lines(self.variant())
+    }
+
+    /// An index, smaller than `VARIANTS`, that identifies the winning lines of these rules
+    pub(crate) fn variant(&self) -> usize {
+        match (self.squares, self.wrap_around) {
+            (false, _) => 0,
+            (true, false) => 1,
+            (true, true) => 2,
+        }
+    }
+}
+
+/// Written as `standard`, or as the enabled options separated by spaces (`squares calling`)
+impl fmt::Display for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let options = [
+            (self.squares, "squares"),
+            (self.wrap_around, "wrap-around"),
+            (self.calling, "calling"),
+        ];
+        let enabled: Vec<&str> = options
+            .iter()
+            .filter(|(enabled, _)| *enabled)
+            .map(|(_, name)| *name)
+            .collect();
+        if enabled.is_empty() {
+            write!(f, "standard")
+        } else {
+            write!(f, "{}", enabled.join(" "))
+        }
+    }
+}
+
+impl FromStr for Rules {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let mut rules = Rules::standard();
+        if s == "standard" {
+            return Ok(rules);
+        }
+        for option in s.split_whitespace() {
+            match option {
+                "squares" => rules.squares = true,
+                "wrap-around" => rules.wrap_around = true,
+                "calling" => rules.calling = true,
+                _ => return Err(ParseError(s.to_owned())),
+            }
+        }
+        Ok(rules)
+    }
+}
+
+/// The winning lines of the given variant
+pub(crate) fn variant_lines(variant: usize) -> &'static [u16] {
+    &WINNING_LINES[..VARIANT_LINES[variant]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_round_trip_through_text() {
+        for options in 0..8 {
+            let rules = Rules {
+                squares: options & 1 != 0,
+                wrap_around: options & 2 != 0,
+                calling: options & 4 != 0,
+            };
+            assert_eq!(rules.to_string().parse::<Rules>().unwrap(), rules);
+        }
+        assert_eq!("standard".parse::<Rules>().unwrap(), Rules::standard());
+        assert!("squares diagonals".parse::<Rules>().is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::random::{self, SeededRng};
 use crate::traits::*;
 use rand::prelude::*;
 
@@ -9,32 +10,52 @@ impl DummyPlayer {
     }
 }
 
+impl Default for DummyPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<S: State, A: Action> Player<S, A> for DummyPlayer {
     type Stats = ();
-    fn take_action(&mut self, _state: S, actions: Vec<A>) -> A {
+    fn take_action(&mut self, _state: S, actions: &[A]) -> A {
         actions[0].clone()
     }
 }
 
-pub struct RandomPlayer {}
+pub struct RandomPlayer {
+    rng: SeededRng,
+}
 
 impl RandomPlayer {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            rng: random::from_entropy(),
+        }
+    }
+}
+
+impl Default for RandomPlayer {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl<S: State, A: Action> Player<S, A> for RandomPlayer {
     type Stats = ();
-    fn take_action(&mut self, _state: S, actions: Vec<A>) -> A {
-        let mut rng = thread_rng();
-        actions.choose(&mut rng).unwrap().clone()
+    fn take_action(&mut self, _state: S, actions: &[A]) -> A {
+        actions.choose(&mut self.rng).unwrap().clone()
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.rng = random::seeded(seed);
     }
 }
 
 pub struct OpponentWrapper<S: State, A: Action, P: Player<S, A>> {
     inner: P,
     epsilon: f32,
+    rng: SeededRng,
     _s: std::marker::PhantomData<S>,
     _a: std::marker::PhantomData<A>,
 }
@@ -44,11 +65,16 @@ impl<S: State, A: Action, P: Player<S, A>> OpponentWrapper<S, A, P> {
         OpponentWrapper {
             inner,
             epsilon,
+            rng: random::from_entropy(),
             _s: std::marker::PhantomData,
             _a: std::marker::PhantomData,
         }
     }
 
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
     pub fn inner_mut(&mut self) -> &mut P {
         &mut self.inner
     }
@@ -56,14 +82,18 @@ impl<S: State, A: Action, P: Player<S, A>> OpponentWrapper<S, A, P> {
 
 impl<S: State, A: Action, P: Player<S, A>> Player<S, A> for OpponentWrapper<S, A, P> {
     type Stats = ();
-    fn take_action(&mut self, state: S, actions: Vec<A>) -> A {
-        if random::<f32>() <= self.epsilon {
+    fn take_action(&mut self, state: S, actions: &[A]) -> A {
+        if self.rng.gen::<f32>() <= self.epsilon {
             // Take a random action
-            let mut rng = thread_rng();
-            actions.choose(&mut rng).unwrap().clone()
+            actions.choose(&mut self.rng).unwrap().clone()
         } else {
             // Delegate
             self.inner.take_action(state, actions)
         }
     }
+
+    fn seed(&mut self, seed: u64) {
+        self.rng = random::seeded(seed);
+        self.inner.seed(self.rng.gen());
+    }
 }
@@ -0,0 +1,483 @@
+//! An exact solver for Quarto positions, based on negamax with alpha-beta pruning
+//!
+//! Values are always from the point of view of the player to move, that is, the one that must place
+//! the reserve piece and then give a piece to the opponent.
+
+use crate::board::*;
+use crate::environment::{bits, Environment, State};
+use crate::simple_players::RandomPlayer;
+use crate::traits::{self, Player};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The exact value of a position
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Serialize)]
+pub enum Value {
+    Loss = -1,
+    Draw = 0,
+    Win = 1,
+}
+
+impl Value {
+    fn from_score(score: i8) -> Self {
+        match score {
+            -1 => Value::Loss,
+            0 => Value::Draw,
+            _ => Value::Win,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Solution {
+    pub value: Value,
+    pub action: Action,
+}
+
+#[derive(Copy, Clone)]
+enum Bound {
+    Exact,
+    Lower,
+    Upper,
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    score: i8,
+    bound: Bound,
+    best_action: u8,
+}
+
+/// A position to be searched: the available positions and pieces are kept as bit masks, so that
+/// moving to a child is just a few bitwise operations
+#[derive(Copy, Clone)]
+struct Node {
+    state: State,
+    positions: u16,
+    pieces: u16,
+}
+
+impl Node {
+    fn child(&self, cell: u8, piece: u8) -> Self {
+        Node {
+            state: self.state.after(cell, piece),
+            positions: self.positions & !(1 << cell),
+            pieces: self.pieces & !(1 << piece),
+        }
+    }
+
+    /// Return the first cell where the reserve piece wins the game, if any
+    fn winning_cell(&self) -> Option<u8> {
+        let reserve = u8::from(self.state.reserve());
+        bits(self.positions).find(|&cell| self.state.wins_with(cell, reserve))
+    }
+
+    /// Return the pieces that would let the opponent win right away, after placing the reserve
+    /// piece at the given cell
+    fn deadly_pieces(&self, cell: u8) -> u16 {
+        let placed = self.state.after(cell, 0);
+        let remaining = self.positions & !(1 << cell);
+        bits(self.pieces)
+            .filter(|&piece| bits(remaining).any(|other| placed.wins_with(other, piece)))
+            .fold(0, |mask, piece| mask | (1 << piece))
+    }
+
+    /// Return all actions that do not lose right away, the most constraining ones first
+    fn safe_actions(&self) -> Vec<u8> {
+        let mut cells: Vec<(u32, u8, u16)> = bits(self.positions)
+            .map(|cell| {
+                let deadly = self.deadly_pieces(cell);
+                (deadly.count_ones(), cell, deadly)
+            })
+            .collect();
+        cells.sort_by_key(|&(num_deadly, cell, _)| (std::cmp::Reverse(num_deadly), cell));
+
+        let mut actions = Vec::new();
+        for (_, cell, deadly) in cells {
+            for piece in bits(self.pieces & !deadly) {
+                actions.push(16 * cell + piece);
+            }
+        }
+        actions
+    }
+
+    /// The first valid action, used when all actions have the same value
+    fn any_action(&self) -> u8 {
+        16 * bits(self.positions).next().unwrap() + bits(self.pieces).next().unwrap_or(0)
+    }
+}
+
+pub struct Solver {
+    table: HashMap<State, Entry>,
+    max_nodes: Option<u64>,
+    nodes: u64,
+}
+
+impl Solver {
+    pub fn new() -> Self {
+        Solver {
+            table: HashMap::new(),
+            max_nodes: None,
+            nodes: 0,
+        }
+    }
+
+    /// Give up searching after visiting this many nodes in a single call, since early positions
+    /// can take a very long time to solve
+    pub fn with_node_limit(max_nodes: u64) -> Self {
+        Solver {
+            max_nodes: Some(max_nodes),
+            ..Self::new()
+        }
+    }
+
+    /// The number of nodes visited by the last search
+    pub fn nodes(&self) -> u64 {
+        self.nodes
+    }
+
+    /// Forget all positions solved so far
+    pub fn clear(&mut self) {
+        self.table.clear();
+    }
+
+    /// Reset the node count. With a node limit, the table is also cleared once it holds more
+    /// positions than the limit, so that its memory stays bounded over many calls
+    fn start_search(&mut self) {
+        self.nodes = 0;
+        if let Some(max_nodes) = self.max_nodes {
+            if self.table.len() as u64 > max_nodes {
+                self.table.clear();
+            }
+        }
+    }
+
+    /// Return the exact value of the position and an optimal action, or `None` if the node limit
+    /// was reached before the position was solved
+    pub fn solve(&mut self, env: &Environment) -> Option<Solution> {
+        self.start_search();
+        let (score, action) = self.search(Node::from(env), -1, 1)?;
+        Some(Solution {
+            value: Value::from_score(score),
+            action: Action::from(action),
+        })
+    }
+
+    /// Return the exact value of every valid action (from the point of view of the player taking
+    /// it), or `None` if the node limit was reached.
+    /// Used as an oracle to check whether other players make optimal decisions
+    pub fn action_values(&mut self, env: &Environment) -> Option<Vec<(Action, Value)>> {
+        self.start_search();
+        let node = Node::from(env);
+        let reserve = u8::from(node.state.reserve());
+        let mut values = Vec::new();
+        for cell in bits(node.positions) {
+            let wins = node.state.wins_with(cell, reserve);
+            let deadly = node.deadly_pieces(cell);
+            for piece in bits(node.pieces) {
+                let value = if wins {
+                    Value::Win
+                } else if deadly & (1 << piece) != 0 {
+                    Value::Loss
+                } else {
+                    Value::from_score(-self.child_score(&node, cell, piece, -1, 1)?)
+                };
+                values.push((Action::from(16 * cell + piece), value));
+            }
+        }
+        Some(values)
+    }
+
+    /// Score of the child position reached with a safe action, from the point of view of the
+    /// opponent
+    fn child_score(&mut self, node: &Node, cell: u8, piece: u8, alpha: i8, beta: i8) -> Option<i8> {
+        let child = node.child(cell, piece);
+        if child.pieces == 0 {
+            // The opponent is forced to place the last piece in the last cell, and the action is
+            // safe, so the board is filled without a winner
+            Some(0)
+        } else {
+            self.search(child, alpha, beta).map(|(score, _)| score)
+        }
+    }
+
+    /// Negamax with alpha-beta pruning. Return the score of the node and its best action
+    fn search(&mut self, node: Node, mut alpha: i8, mut beta: i8) -> Option<(i8, u8)> {
+        self.nodes += 1;
+        if let Some(max_nodes) = self.max_nodes {
+            if self.nodes > max_nodes {
+                return None;
+            }
+        }
+
+        if let Some(cell) = node.winning_cell() {
+            return Some((1, 16 * cell + bits(node.pieces).next().unwrap_or(0)));
+        }
+
+        let original_alpha = alpha;
+        let mut previous_best = None;
+        if let Some(entry) = self.table.get(&node.state) {
+            match entry.bound {
+                Bound::Exact => return Some((entry.score, entry.best_action)),
+                Bound::Lower => alpha = alpha.max(entry.score),
+                Bound::Upper => beta = beta.min(entry.score),
+            }
+            if alpha >= beta {
+                return Some((entry.score, entry.best_action));
+            }
+            previous_best = Some(entry.best_action);
+        }
+
+        let mut actions = node.safe_actions();
+        if let Some(previous_best) = previous_best {
+            // Try the best action from a previous search first
+            if let Some(i) = actions.iter().position(|&a| a == previous_best) {
+                actions[..=i].rotate_right(1);
+            }
+        }
+
+        let mut best = None;
+        for action in actions {
+            let score = -self.child_score(&node, action / 16, action % 16, -beta, -alpha)?;
+            if best.is_none_or(|(best_score, _)| score > best_score) {
+                best = Some((score, action));
+            }
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                break;
+            }
+        }
+        // Without safe actions, every action loses
+        let best = best.unwrap_or((-1, node.any_action()));
+
+        let bound = if best.0 <= original_alpha {
+            Bound::Upper
+        } else if best.0 >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        self.table.insert(
+            node.state,
+            Entry {
+                score: best.0,
+                bound,
+                best_action: best.1,
+            },
+        );
+        Some(best)
+    }
+}
+
+impl Default for Solver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&Environment> for Node {
+    fn from(env: &Environment) -> Self {
+        Node {
+            state: env.state(),
+            positions: env.available_positions(),
+            pieces: env.available_pieces(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SolverStats {
+    pub solved_actions: u32,
+    pub unsolved_actions: u32,
+    pub nodes: u64,
+}
+
+/// A player that picks optimal actions whenever the solver can finish in time, and otherwise takes
+/// the first action that does not lose right away
+pub struct SolverPlayer {
+    solver: Solver,
+    stats: SolverStats,
+}
+
+impl SolverPlayer {
+    pub fn new(solver: Solver) -> Self {
+        SolverPlayer {
+            solver,
+            stats: SolverStats {
+                solved_actions: 0,
+                unsolved_actions: 0,
+                nodes: 0,
+            },
+        }
+    }
+}
+
+impl traits::Player<State, Action> for SolverPlayer {
+    type Stats = SolverStats;
+
+    fn take_action(&mut self, state: State, actions: &[Action]) -> Action {
+        let env = Environment::from_state(state);
+        let solution = self.solver.solve(&env);
+        self.stats.nodes += self.solver.nodes();
+        match solution {
+            Some(solution) => {
+                self.stats.solved_actions += 1;
+                solution.action
+            }
+            None => {
+                self.stats.unsolved_actions += 1;
+                let node = Node::from(&env);
+                let action = node
+                    .winning_cell()
+                    .map(|cell| 16 * cell + bits(node.pieces).next().unwrap_or(0))
+                    .or_else(|| node.safe_actions().first().cloned());
+                action.map_or(actions[0], Action::from)
+            }
+        }
+    }
+
+    /// Positions of a game rarely show up in the next one, so they are forgotten
+    fn end(&mut self, _state: State, _reward: f32) {
+        self.solver.clear();
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats.solved_actions = 0;
+        self.stats.unsolved_actions = 0;
+        self.stats.nodes = 0;
+    }
+
+    fn stats(&self) -> Option<SolverStats> {
+        Some(self.stats.clone())
+    }
+}
+
+/// Measure how often a player picks an optimal action. The positions are sampled from random
+/// games, at the given depth, keeping only the ones the solver can handle.
+/// Return the fraction of optimal actions and the number of positions evaluated
+pub fn optimal_action_rate<P: Player<State, Action>>(
+    player: &mut P,
+    solver: &mut Solver,
+    positions: u32,
+    depth: u16,
+    seed: u64,
+) -> (f32, u32) {
+    let mut random_player = RandomPlayer::new();
+    Player::<State, Action>::seed(&mut random_player, seed);
+    let (mut optimal, mut evaluated) = (0, 0);
+    for _ in 0..positions {
+        // Play randomly up to the given depth
+        let mut env = Environment::new();
+        let (mut state, _) = traits::Environment::reset(&mut env);
+        let mut done = false;
+        while !done && traits::State::game_depth(&state) < depth {
+            let action = random_player.take_action(state, env.actions());
+            let (next_state, _, next_done, _) = traits::Environment::step(&mut env, action);
+            state = next_state;
+            done = next_done;
+        }
+        if done {
+            continue;
+        }
+
+        if let Some(values) = solver.action_values(&env) {
+            let best = values.iter().map(|(_, value)| *value).max().unwrap();
+            let action = player.take_action(state, env.actions());
+            evaluated += 1;
+            if values.contains(&(action, best)) {
+                optimal += 1;
+            }
+        }
+    }
+    (optimal as f32 / evaluated.max(1) as f32, evaluated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Rules;
+    use crate::traits::Environment as _;
+
+    fn action(row: u8, col: u8, piece: u8) -> Action {
+        Action {
+            position: Position { row, col },
+            piece: Piece::from(piece),
+        }
+    }
+
+    /// Exhaustive negamax, from the point of view of the player to move
+    fn brute_force(env: &Environment) -> i8 {
+        let mut best = -1;
+        for &action in env.actions() {
+            let mut child = env.clone();
+            let (_, reward, done, _) = child.step(action);
+            let score = if done {
+                reward.partial_cmp(&0.).unwrap() as i8
+            } else {
+                -brute_force(&child)
+            };
+            best = best.max(score);
+        }
+        best
+    }
+
+    #[test]
+    fn finds_an_immediate_win() {
+        // Three black pieces on the first row, and a black one to place
+        let mut env = Environment::new();
+        env.reset();
+        for (col, piece) in [(0, 14), (1, 13), (2, 12)] {
+            env.step(action(0, col, piece));
+        }
+        let solution = Solver::new().solve(&env).unwrap();
+        assert_eq!(solution.value, Value::Win);
+        assert_eq!(solution.action.position, Position { row: 0, col: 3 });
+    }
+
+    #[test]
+    fn solves_positions_from_text() {
+        let env =
+            Environment::from_position("WTRFWTRHWTQF./..../..../.... WSQH", Rules::standard())
+                .unwrap();
+        let solution = Solver::new().solve(&env).unwrap();
+        assert_eq!(solution.value, Value::Win);
+        assert_eq!(solution.action.position.to_string(), "d4");
+
+        let actions: Vec<Action> = ["a1 BSQF", "b1 BTQH", "c1 BTRH"]
+            .iter()
+            .map(|action| action.parse().unwrap())
+            .collect();
+        let env = Environment::from_actions(&actions, Rules::standard()).unwrap();
+        let solution = Solver::new().solve(&env).unwrap();
+        assert_eq!(solution.value, Value::Win);
+        assert_eq!(solution.action.position.to_string(), "d1");
+    }
+
+    #[test]
+    fn agrees_with_exhaustive_search() {
+        let mut solver = Solver::new();
+        let mut values = Vec::new();
+        for game in 0..40 {
+            // Play a deterministic but varied game up to 11 pieces
+            let mut env = Environment::new();
+            env.reset();
+            let mut done = false;
+            for i in 0..11 {
+                let action = env.actions()[(i * (2 * game + 3) + game) % env.actions().len()];
+                done = env.step(action).2;
+                if done {
+                    break;
+                }
+            }
+            if done {
+                continue;
+            }
+
+            let value = solver.solve(&env).unwrap().value;
+            assert_eq!(value as i8, brute_force(&env), "{}", env.state());
+            values.push(value);
+        }
+        assert!(values.len() >= 10);
+        assert!(values.contains(&Value::Win) && values.contains(&Value::Loss));
+    }
+}
@@ -0,0 +1,510 @@
+//! Quarto with every turn split in two decisions: placing the reserve piece, then giving a piece
+//! to the opponent
+//!
+//! Each decision has at most 16 choices, instead of the up to 240 actions of `environment`, at the
+//! cost of twice as many decisions per game. Both formulations play the same game, so players
+//! trained on each of them can be compared.
+//!
+//! This environment also supports the calling rule (see `Rules::calling`): a Quarto only wins once
+//! it is claimed, either by the player that made it, right after placing the piece, or by the
+//! opponent, before placing the next one.
+
+use crate::board::*;
+use crate::environment::{bits, Opening, Outcome, State, Variant};
+use crate::persist::StateKind;
+use crate::random::{self, SeededRng};
+use crate::record::GameResult;
+use crate::rules::Rules;
+use crate::symmetry::Symmetry;
+use crate::traits;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The decision to be taken in a state
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, Serialize, Deserialize)]
+pub enum Phase {
+    /// Put the reserve piece on the board
+    Place,
+    /// Choose the reserve piece of the opponent
+    Give,
+}
+
+/// The board and the decision to be taken. In the give phase, the reserve of the board is the piece
+/// that was just placed (or `BSQH` when the first piece is chosen by the first player)
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, Serialize, Deserialize)]
+pub struct SplitState {
+    board: State,
+    phase: Phase,
+    /// The last piece placed made a Quarto that can still be claimed (calling rule only)
+    claimable: bool,
+    /// A Quarto was claimed, which ends the game (calling rule only)
+    claimed: bool,
+    /// The first piece was given by the first player (`Opening::Choice`), so the second player
+    /// places the odd pieces
+    first_piece_given: bool,
+}
+
+impl SplitState {
+    fn new(rules: Rules) -> Self {
+        SplitState {
+            board: State::new(rules),
+            phase: Phase::Place,
+            claimable: false,
+            claimed: false,
+            first_piece_given: false,
+        }
+    }
+
+    pub fn board(&self) -> State {
+        self.board
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn claimable(&self) -> bool {
+        self.claimable
+    }
+
+    /// Bit mask of the pieces that can still be given
+    fn available_pieces(&self) -> u16 {
+        match self.phase {
+            Phase::Place => !self.board.used_pieces(),
+            // The reserve is already on the board, unless no piece was given yet
+            Phase::Give => !self.board.placed_pieces(),
+        }
+    }
+}
+
+impl StateKind for SplitState {
+    const NAME: &'static str = "split";
+}
+
+impl traits::State for SplitState {
+    /// The number of decisions taken since the start of the game, counting the choice of the first
+    /// piece even when it is not taken by a player
+    fn game_depth(&self) -> u16 {
+        let placed = traits::State::game_depth(&self.board) * 2;
+        match self.phase {
+            Phase::Place => placed + 1,
+            Phase::Give => placed,
+        }
+    }
+
+    /// The player that placed a piece also gives the next one
+    fn continues_turn(&self) -> bool {
+        self.phase == Phase::Give
+    }
+}
+
+impl traits::Canonical<SplitAction> for SplitState {
+    type Transform = Symmetry;
+
+    /// Symmetric boards share the same canonical state, in both phases
+    fn canonical(&self) -> (Self, Symmetry) {
+        let (board, symmetry) = traits::Canonical::<Action>::canonical(&self.board);
+        (SplitState { board, ..*self }, symmetry)
+    }
+}
+
+/// One of the two decisions of a turn, or a claim under the calling rule.
+/// Claims are ordered first, so that players without a preference (like `DummyPlayer`) claim
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub enum SplitAction {
+    /// Claim the Quarto made by the last piece placed
+    Claim,
+    Place(Position),
+    Give(Piece),
+}
+
+impl traits::Action for SplitAction {}
+
+/// Written as the position (`c3`), the piece (`WTRF`) or `quarto` for a claim, see `board` for the
+/// notation
+impl fmt::Display for SplitAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SplitAction::Place(position) => write!(f, "{}", position),
+            SplitAction::Give(piece) => write!(f, "{}", piece),
+            SplitAction::Claim => write!(f, "quarto"),
+        }
+    }
+}
+
+impl FromStr for SplitAction {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        if s.eq_ignore_ascii_case("quarto") {
+            return Ok(SplitAction::Claim);
+        }
+        match s.parse() {
+            Ok(position) => Ok(SplitAction::Place(position)),
+            Err(_) => s.parse().map(SplitAction::Give),
+        }
+    }
+}
+
+impl traits::Transform<SplitAction> for Symmetry {
+    fn apply(&self, action: &SplitAction) -> SplitAction {
+        match *action {
+            SplitAction::Place(position) => SplitAction::Place(self.apply_position(position)),
+            SplitAction::Give(piece) => SplitAction::Give(self.apply_piece(piece)),
+            SplitAction::Claim => SplitAction::Claim,
+        }
+    }
+
+    fn inverse(&self) -> Self {
+        traits::Transform::<Action>::inverse(self)
+    }
+}
+
+/// An environment where each player places the reserve piece and then gives the next one, in two
+/// separate actions. The last piece is placed by the player that receives it, like any other
+#[derive(Clone)]
+pub struct SplitEnvironment {
+    state: SplitState,
+    /// Bit mask of the empty cells
+    available_positions: u16,
+    /// Bit mask of the pieces that can still be given to the opponent
+    available_pieces: u16,
+    /// The actions applied since the environment was reset or created, used to undo them
+    history: Vec<Move>,
+    /// The valid actions, updated after every change
+    actions: Vec<SplitAction>,
+    opening: Opening,
+    /// Only used for random openings, created when first needed if not seeded
+    rng: Option<SeededRng>,
+}
+
+/// An applied action, with what is needed to restore the environment as it was before it
+#[derive(Copy, Clone)]
+struct Move {
+    action: SplitAction,
+    state: SplitState,
+    available_positions: u16,
+    available_pieces: u16,
+}
+
+impl SplitEnvironment {
+    pub fn new() -> Self {
+        SplitEnvironment::with_rules(Rules::standard())
+    }
+
+    pub fn with_rules(rules: Rules) -> Self {
+        SplitEnvironment {
+            state: SplitState::new(rules),
+            available_positions: 0,
+            available_pieces: 0,
+            history: Vec::new(),
+            actions: Vec::new(),
+            opening: Opening::default(),
+            rng: None,
+        }
+    }
+
+    /// Set how the first piece is picked on `reset()`. With `Opening::Choice`, the game starts in
+    /// the give phase, with an empty board
+    pub fn opening(mut self, opening: Opening) -> Self {
+        self.opening = opening;
+        self
+    }
+
+    /// Create an environment in the given state. The available positions and pieces are derived
+    /// from the board
+    pub fn from_state(state: SplitState) -> Self {
+        let mut env = SplitEnvironment {
+            state,
+            available_positions: state.board.empty_cells(),
+            available_pieces: state.available_pieces(),
+            history: Vec::new(),
+            actions: Vec::new(),
+            opening: Opening::default(),
+            rng: None,
+        };
+        env.update_actions();
+        env
+    }
+
+    /// Check whether the game is over, either won or with the board full and nothing to claim
+    pub fn is_over(&self) -> bool {
+        if self.state.board.rules().calling {
+            self.state.claimed || (self.available_positions == 0 && !self.state.claimable)
+        } else {
+            self.available_positions == 0 || self.state.board.has_quarto()
+        }
+    }
+
+    pub fn state(&self) -> SplitState {
+        self.state
+    }
+
+    /// Describe how the game ended, or return `None` if it is not over
+    pub fn outcome(&self) -> Option<Outcome> {
+        if !self.is_over() {
+            return None;
+        }
+        // The first player places the odd pieces, unless it gave the first one
+        let placed = traits::State::game_depth(&self.state.board);
+        let first_player_placed = (placed % 2 == 1) != self.state.first_piece_given;
+        if !self.state.board.rules().calling {
+            return Some(self.state.board.outcome(None, first_player_placed));
+        }
+        if !self.state.claimed {
+            // The board is full and any Quarto on it was not claimed in time
+            return Some(Outcome {
+                result: GameResult::Draw,
+                quartos: Vec::new(),
+            });
+        }
+        // Older Quartos may be left on the board, so only keep the ones of the last piece if it is
+        // known. The Quarto is stolen if it is claimed before the opponent gives a piece
+        let last_cell = self
+            .history
+            .iter()
+            .rev()
+            .find_map(|applied| match applied.action {
+                SplitAction::Place(position) => Some(u8::from(position)),
+                _ => None,
+            });
+        let stolen = self.state.phase == Phase::Place;
+        Some(
+            self.state
+                .board
+                .outcome(last_cell, first_player_placed != stolen),
+        )
+    }
+
+    /// Return valid actions for the current state
+    pub fn actions(&self) -> &[SplitAction] {
+        &self.actions
+    }
+
+    /// The actions applied since the environment was reset or created, from the first to the last
+    pub fn history(&self) -> impl Iterator<Item = SplitAction> + '_ {
+        self.history.iter().map(|applied| applied.action)
+    }
+
+    /// Fill the list of valid actions, reusing its memory
+    fn update_actions(&mut self) {
+        self.actions.clear();
+        if self.is_over() {
+            return;
+        }
+        if self.state.claimable {
+            self.actions.push(SplitAction::Claim);
+        }
+        match self.state.phase {
+            Phase::Place => self.actions.extend(
+                bits(self.available_positions).map(|cell| SplitAction::Place(Position::from(cell))),
+            ),
+            Phase::Give => self.actions.extend(
+                bits(self.available_pieces).map(|piece| SplitAction::Give(Piece::from(piece))),
+            ),
+        }
+    }
+}
+
+impl Default for SplitEnvironment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl traits::Environment for SplitEnvironment {
+    type State = SplitState;
+    type Action = SplitAction;
+
+    fn reset(&mut self) -> (SplitState, &[SplitAction]) {
+        let mut state = SplitState::new(self.state.board.rules());
+        match self.opening.piece(&mut self.rng) {
+            Some(piece) => state.board = state.board.with_reserve(piece),
+            None => {
+                state.phase = Phase::Give;
+                state.first_piece_given = true;
+            }
+        }
+        self.state = state;
+        self.history.clear();
+        self.available_positions = 0xFFFF;
+        self.available_pieces = state.available_pieces();
+        self.update_actions();
+        (self.state, &self.actions)
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.rng = Some(random::seeded(seed));
+    }
+
+    fn step(&mut self, action: SplitAction) -> (SplitState, f32, bool, &[SplitAction]) {
+        self.history.push(Move {
+            action,
+            state: self.state,
+            available_positions: self.available_positions,
+            available_pieces: self.available_pieces,
+        });
+
+        let board = self.state.board;
+        let calling = board.rules().calling;
+        let (reward, done) = match action {
+            SplitAction::Place(position) => {
+                assert_eq!(self.state.phase, Phase::Place);
+                let cell = u8::from(position);
+                assert_ne!(self.available_positions & (1 << cell), 0);
+                self.available_positions &= !(1 << cell);
+                let reserve = u8::from(board.reserve());
+                let won = board.wins_with(cell, reserve);
+                // A Quarto that was not claimed cannot be claimed anymore
+                self.state = SplitState {
+                    board: board.after(cell, reserve),
+                    phase: Phase::Give,
+                    claimable: calling && won,
+                    claimed: false,
+                    ..self.state
+                };
+                if won && !calling {
+                    (100., true)
+                } else {
+                    (0., self.available_positions == 0 && !self.state.claimable)
+                }
+            }
+            SplitAction::Give(piece) => {
+                assert_eq!(self.state.phase, Phase::Give);
+                let piece = u8::from(piece);
+                assert_ne!(self.available_pieces & (1 << piece), 0);
+                self.available_pieces &= !(1 << piece);
+                self.state = SplitState {
+                    board: board.with_reserve(piece),
+                    phase: Phase::Place,
+                    ..self.state
+                };
+                (0., false)
+            }
+            SplitAction::Claim => {
+                assert!(self.state.claimable);
+                self.state.claimable = false;
+                self.state.claimed = true;
+                (100., true)
+            }
+        };
+
+        self.update_actions();
+        (self.state, reward, done, &self.actions)
+    }
+}
+
+impl traits::Reversible for SplitEnvironment {
+    fn undo(&mut self) -> Option<SplitAction> {
+        let applied = self.history.pop()?;
+        self.state = applied.state;
+        self.available_positions = applied.available_positions;
+        self.available_pieces = applied.available_pieces;
+        self.update_actions();
+        Some(applied.action)
+    }
+}
+
+impl Variant for SplitEnvironment {
+    fn variant(rules: Rules, opening: Opening) -> Self {
+        SplitEnvironment::with_rules(rules).opening(opening)
+    }
+}
+
+impl traits::Simulator for SplitEnvironment {
+    fn from_state(state: &SplitState) -> Self {
+        SplitEnvironment::from_state(*state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::Environment as _;
+
+    /// Apply the space separated actions and return the reward and end of the last one
+    fn play(env: &mut SplitEnvironment, actions: &str) -> (f32, bool) {
+        let mut last = (0., false);
+        for action in actions.split_whitespace() {
+            let (_, reward, done, _) = env.step(action.parse().unwrap());
+            last = (reward, done);
+        }
+        last
+    }
+
+    /// The result of a finished game, checking that an environment rebuilt from its state agrees
+    fn result(env: &SplitEnvironment) -> GameResult {
+        let result = env.outcome().unwrap().result;
+        let rebuilt = SplitEnvironment::from_state(env.state());
+        assert_eq!(rebuilt.outcome().unwrap().result, result);
+        result
+    }
+
+    /// A game with the calling rule where the second player just made a Quarto on the first row,
+    /// and did not claim it yet
+    fn missed_quarto() -> SplitEnvironment {
+        let mut env = SplitEnvironment::with_rules(Rules {
+            calling: true,
+            ..Rules::standard()
+        });
+        env.reset();
+        assert_eq!(play(&mut env, "a1 BSQF b1 BSRH c1 BSRF d1"), (0., false));
+        assert!(env.state().claimable());
+        env
+    }
+
+    #[test]
+    fn quartos_win_right_away_without_the_calling_rule() {
+        let mut env = SplitEnvironment::new();
+        env.reset();
+        assert_eq!(play(&mut env, "a1 BSQF b1 BSRH c1 BSRF d1"), (100., true));
+        assert_eq!(result(&env), GameResult::SecondPlayerWins);
+    }
+
+    #[test]
+    fn claimed_quartos_win() {
+        let mut env = missed_quarto();
+        assert!(env.actions().contains(&SplitAction::Claim));
+        assert_eq!(play(&mut env, "quarto"), (100., true));
+        assert!(env.actions().is_empty());
+        assert_eq!(result(&env), GameResult::SecondPlayerWins);
+        assert_eq!(env.outcome().unwrap().quartos.len(), 1);
+    }
+
+    #[test]
+    fn missed_quartos_can_be_stolen() {
+        let mut env = missed_quarto();
+        assert_eq!(play(&mut env, "WTRF"), (0., false));
+        assert_eq!(env.actions()[0], SplitAction::Claim);
+        assert_eq!(play(&mut env, "quarto"), (100., true));
+        assert_eq!(result(&env), GameResult::FirstPlayerWins);
+    }
+
+    #[test]
+    fn missed_quartos_expire() {
+        let mut env = missed_quarto();
+        assert_eq!(play(&mut env, "WTRF a2"), (0., false));
+        assert!(!env.state().claimable());
+        assert!(!env.actions().contains(&SplitAction::Claim));
+        assert!(!env.is_over());
+        assert!(env.outcome().is_none());
+    }
+
+    #[test]
+    fn the_first_player_wins_with_the_last_piece_after_choosing_the_first_one() {
+        for &calling in &[false, true] {
+            let rules = Rules {
+                calling,
+                ..Rules::standard()
+            };
+            let mut env = SplitEnvironment::with_rules(rules).opening(Opening::Choice);
+            env.reset();
+            play(&mut env, "BSQH a1 BSQF b1 BSRH c1 BSRF d1");
+            if calling {
+                play(&mut env, "quarto");
+            }
+            assert_eq!(result(&env), GameResult::FirstPlayerWins);
+        }
+    }
+}
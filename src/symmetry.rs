@@ -0,0 +1,282 @@
+//! Symmetries of the Quarto game
+//!
+//! The board has 32 permutations of its cells that keep every row, column and diagonal a line:
+//! the rotations and reflections of the square, combined with swapping the two middle
+//! rows/columns ("inside-out") and swapping the first two rows/columns with the last two
+//! ("mid-flip").
+//! The pieces can have their attributes permuted and flipped in any way.
+//! Since a win only depends on four aligned pieces sharing an attribute, combining both gives an
+//! equivalent game.
+//! When 2x2 squares also win, only the permutations that keep the squares are used: the 8
+//! rotations and reflections, plus the mid-flip ones with wrap-around squares.
+
+use crate::board::*;
+use crate::rules::{self, Rules, VARIANTS};
+use crate::traits;
+use std::sync::OnceLock;
+
+/// A symmetry of the game: a permutation of the board cells plus a transformation of the pieces
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct Symmetry {
+    /// Index into `Tables::boards`
+    board: u8,
+    /// Index into `Tables::attributes`
+    attributes: u8,
+    /// Attributes that are flipped after being permuted
+    flip: u8,
+}
+
+struct Tables {
+    /// The cell `i` is moved to the cell `boards[_][i]`
+    boards: Vec<[u8; 16]>,
+    board_inverses: Vec<u8>,
+    /// The indexes of the boards that keep the winning lines of each variant of the rules
+    variant_boards: Vec<Vec<u8>>,
+    /// The attribute `i` of a transformed piece is the attribute `attributes[_][i]` of the
+    /// original one
+    attributes: Vec<[u8; 4]>,
+    attribute_inverses: Vec<u8>,
+    /// Pre-computed permutation of every piece
+    attribute_pieces: Vec<[u8; 16]>,
+}
+
+impl Symmetry {
+    pub fn identity() -> Self {
+        Symmetry {
+            board: 0,
+            attributes: 0,
+            flip: 0,
+        }
+    }
+
+    /// Iterate over all symmetries of the game played with the given rules that transform the
+    /// given piece into the piece `0`.
+    /// Every state is equivalent to exactly one state per such symmetry
+    pub fn normalizing(piece: Piece, rules: Rules) -> impl Iterator<Item = Symmetry> {
+        let tables = tables();
+        let piece = u8::from(piece);
+        let boards = &tables.variant_boards[rules.variant()];
+        boards.iter().flat_map(move |&board| {
+            (0..tables.attributes.len() as u8).map(move |attributes| Symmetry {
+                board,
+                attributes,
+                flip: tables.attribute_pieces[attributes as usize][piece as usize],
+            })
+        })
+    }
+
+    pub fn apply_position(&self, position: Position) -> Position {
+        Position::from(self.apply_cell(u8::from(position)))
+    }
+
+    pub fn apply_piece(&self, piece: Piece) -> Piece {
+        Piece::from(self.apply_piece_u8(u8::from(piece)))
+    }
+
+    /// Same as `apply_position()`, but operating on the `u8` representation
+    pub fn apply_cell(&self, cell: u8) -> u8 {
+        tables().boards[self.board as usize][cell as usize]
+    }
+
+    /// Same as `apply_piece()`, but operating on the `u8` representation
+    pub fn apply_piece_u8(&self, piece: u8) -> u8 {
+        tables().attribute_pieces[self.attributes as usize][piece as usize] ^ self.flip
+    }
+}
+
+impl traits::Transform<Action> for Symmetry {
+    fn apply(&self, action: &Action) -> Action {
+        Action {
+            position: self.apply_position(action.position),
+            piece: self.apply_piece(action.piece),
+        }
+    }
+
+    fn inverse(&self) -> Self {
+        let tables = tables();
+        let attributes = tables.attribute_inverses[self.attributes as usize];
+        Symmetry {
+            board: tables.board_inverses[self.board as usize],
+            attributes,
+            // p' = P(p) ^ f  <=>  p = P⁻¹(p') ^ P⁻¹(f)
+            flip: tables.attribute_pieces[attributes as usize][self.flip as usize],
+        }
+    }
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let boards = board_permutations();
+        let board_inverses = inverses(&boards);
+        let variant_boards = (0..VARIANTS)
+            .map(|variant| {
+                let lines = rules::variant_lines(variant);
+                (0..boards.len() as u8)
+                    .filter(|&board| keeps_lines(&boards[board as usize], lines))
+                    .collect()
+            })
+            .collect();
+        let attributes = permutations();
+        let attribute_inverses = inverses(&attributes);
+        let attribute_pieces = attributes
+            .iter()
+            .map(|permutation| {
+                let mut pieces = [0; 16];
+                for (piece, permuted) in pieces.iter_mut().enumerate() {
+                    for (i, &from) in permutation.iter().enumerate() {
+                        *permuted |= ((piece as u8 >> from) & 1) << i;
+                    }
+                }
+                pieces
+            })
+            .collect();
+        Tables {
+            boards,
+            board_inverses,
+            variant_boards,
+            attributes,
+            attribute_inverses,
+            attribute_pieces,
+        }
+    })
+}
+
+/// All permutations of the 16 cells that map the rows, columns and diagonals into each other,
+/// starting with the identity
+fn board_permutations() -> Vec<[u8; 16]> {
+    let lines = Rules::standard().lines();
+    let mut boards = Vec::new();
+    for rows in permutations() {
+        for cols in permutations() {
+            for &transpose in &[false, true] {
+                let mut board = [0; 16];
+                for (cell, moved) in board.iter_mut().enumerate() {
+                    let (row, col) = (rows[cell / 4], cols[cell % 4]);
+                    *moved = if transpose {
+                        4 * col + row
+                    } else {
+                        4 * row + col
+                    };
+                }
+
+                if keeps_lines(&board, lines) && !boards.contains(&board) {
+                    boards.push(board);
+                }
+            }
+        }
+    }
+    debug_assert_eq!(boards.len(), 32);
+    boards
+}
+
+/// Check whether the permutation of the cells maps every line into one of the lines
+fn keeps_lines(board: &[u8; 16], lines: &[u16]) -> bool {
+    lines.iter().all(|&line| {
+        let moved = (0..16)
+            .filter(|&cell| line & (1 << cell) != 0)
+            .fold(0u16, |mask, cell| mask | (1 << board[cell]));
+        lines.contains(&moved)
+    })
+}
+
+/// All 24 permutations of 4 elements, starting with the identity
+fn permutations() -> Vec<[u8; 4]> {
+    let mut permutations = Vec::with_capacity(24);
+    for a in 0..4 {
+        for b in 0..4 {
+            for c in 0..4 {
+                if a != b && a != c && b != c {
+                    permutations.push([a, b, c, 6 - a - b - c]);
+                }
+            }
+        }
+    }
+    permutations
+}
+
+/// For each permutation, find the index of its inverse
+fn inverses<T: AsRef<[u8]> + PartialEq>(permutations: &[T]) -> Vec<u8> {
+    permutations
+        .iter()
+        .map(|permutation| {
+            let permutation = permutation.as_ref();
+            let mut inverse = vec![0; permutation.len()];
+            for (i, &to) in permutation.iter().enumerate() {
+                inverse[to as usize] = i as u8;
+            }
+            permutations
+                .iter()
+                .position(|other| other.as_ref() == &inverse[..])
+                .unwrap() as u8
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::Transform;
+
+    fn all_rules() -> [Rules; VARIANTS] {
+        [
+            Rules::standard(),
+            Rules::advanced(),
+            Rules {
+                squares: true,
+                wrap_around: true,
+                ..Rules::standard()
+            },
+        ]
+    }
+
+    /// A sample of the symmetries, with every way of flipping the attributes
+    fn symmetries() -> impl Iterator<Item = Symmetry> {
+        (0..16).flat_map(|piece| {
+            Symmetry::normalizing(Piece::from(piece), Rules::standard()).step_by(29)
+        })
+    }
+
+    #[test]
+    fn board_permutations_keep_the_lines_of_their_variant() {
+        let tables = tables();
+        assert_eq!(tables.variant_boards[0].len(), 32);
+        for rules in &all_rules() {
+            let lines = rules.lines();
+            for &board in &tables.variant_boards[rules.variant()] {
+                let board = &tables.boards[board as usize];
+                for &line in lines {
+                    let moved = (0..16)
+                        .filter(|&cell| line & (1 << cell) != 0)
+                        .fold(0u16, |mask, cell| mask | (1 << board[cell]));
+                    assert!(lines.contains(&moved), "{:?} breaks {:016b}", board, line);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_apply() {
+        for symmetry in symmetries() {
+            let inverse = Transform::<Action>::inverse(&symmetry);
+            for v in 0..=255 {
+                let action = Action::from(v);
+                assert_eq!(inverse.apply(&symmetry.apply(&action)), action);
+            }
+        }
+    }
+
+    #[test]
+    fn normalizing_maps_the_piece_to_zero() {
+        for rules in &all_rules() {
+            let boards = tables().variant_boards[rules.variant()].len();
+            for piece in (0..16).map(Piece::from) {
+                let symmetries: Vec<Symmetry> = Symmetry::normalizing(piece, *rules).collect();
+                assert_eq!(symmetries.len(), boards * 24);
+                for symmetry in symmetries {
+                    assert_eq!(symmetry.apply_piece(piece), Piece::from(0));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Play Quarto against any player in the terminal
+//!
+//! Moves are typed as the square where the reserve piece is placed followed by the piece handed
+//! over to the opponent, for example `c3 WTRF` (see `board` for the notation).
+
+use crate::board::*;
+use crate::environment::{bits, Environment, State};
+use crate::rules::Rules;
+use crate::traits::{self, Player};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+
+/// Draw the board, with the fourth row at the top
+pub fn render_board(state: &State) -> String {
+    let mut text = String::from("     a    b    c    d\n");
+    for row in (0..4).rev() {
+        write!(text, "{} ", row + 1).unwrap();
+        for col in 0..4 {
+            match state.piece(Position { row, col }) {
+                Some(piece) => write!(text, " {}", piece).unwrap(),
+                None => text.push_str(" ----"),
+            }
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Draw the board followed by the piece to place and the pieces that can still be given
+pub fn render(state: &State) -> String {
+    let mut text = render_board(state);
+    writeln!(text, "To place: {}", state.reserve()).unwrap();
+    text.push_str("To give:");
+    for piece in bits(!state.used_pieces()) {
+        write!(text, " {}", Piece::from(piece)).unwrap();
+    }
+    text.push('\n');
+    text
+}
+
+/// Play a game between a human, reading moves from `input`, and the given opponent.
+/// Return the score of the human, or `None` if they quit before the end of the game
+pub fn play<P, R, W>(
+    opponent: &mut P,
+    rules: Rules,
+    human_starts: bool,
+    mut input: R,
+    mut output: W,
+) -> io::Result<Option<f32>>
+where
+    P: Player<State, Action>,
+    R: BufRead,
+    W: Write,
+{
+    let mut env = Environment::with_rules(rules);
+    let (mut state, mut actions) = traits::Environment::reset(&mut env);
+    let mut human_turn = human_starts;
+    let mut opponent_started = false;
+    loop {
+        let action = if human_turn {
+            writeln!(output, "\n{}", render(&state))?;
+            match ask_action(&env, &mut input, &mut output)? {
+                Some(action) => action,
+                None => return Ok(None),
+            }
+        } else {
+            let action = if opponent_started {
+                opponent.step(state, actions, 0.)
+            } else {
+                opponent_started = true;
+                opponent.start(state, actions)
+            };
+            writeln!(
+                output,
+                "\nOpponent placed {} at {} and gave {}",
+                state.reserve(),
+                action.position,
+                action.piece
+            )?;
+            action
+        };
+
+        // The opponent could be a player loaded from anywhere, so its actions are checked too
+        let (next_state, reward, done, next_actions) = env
+            .try_step(action)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        if done {
+            let score = if human_turn { reward } else { -reward };
+            opponent.end(next_state, -score);
+            writeln!(output, "\n{}", render_board(&next_state))?;
+            for quarto in env
+                .outcome()
+                .map(|outcome| outcome.quartos)
+                .unwrap_or_default()
+            {
+                writeln!(output, "Quarto: {} {}", quarto.kind, quarto)?;
+            }
+            let result = if score > 0. {
+                "You won!"
+            } else if score < 0. {
+                "You lost"
+            } else {
+                "Draw"
+            };
+            writeln!(output, "{}", result)?;
+            return Ok(Some(score));
+        }
+        state = next_state;
+        actions = next_actions;
+        human_turn = !human_turn;
+    }
+}
+
+/// Read moves until a valid one is entered. Return `None` at the end of the input or if the human
+/// quits
+fn ask_action<R: BufRead, W: Write>(
+    env: &Environment,
+    input: &mut R,
+    output: &mut W,
+) -> io::Result<Option<Action>> {
+    loop {
+        write!(
+            output,
+            "Place {} at a square and give a piece (for example `c3 WTRF`), or `quit`: ",
+            env.state().reserve()
+        )?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim();
+        if line == "quit" {
+            return Ok(None);
+        }
+        match line.parse::<Action>() {
+            Ok(action) => match env.check(action) {
+                Ok(()) => return Ok(Some(action)),
+                Err(error) => writeln!(output, "{}", error)?,
+            },
+            Err(error) => writeln!(output, "{}", error)?,
+        }
+    }
+}
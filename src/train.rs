@@ -1,37 +1,197 @@
+use crate::environment::{Opening, Variant};
+use crate::persist::{self, Format, StateKind};
+use crate::random::{self, SeededRng};
+use crate::record::{GameRecord, GameResult};
+use crate::rules::Rules;
 use crate::simple_players::*;
 use crate::traits::*;
-use std::fs::File;
+use rand::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
+use std::io::{self, SeekFrom};
 
-/// Train a given player against itself
+/// Where and how often `train()` saves its progress
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CheckpointConfig {
+    pub every_cycles: u32,
+    pub file_name: String,
+}
+
+/// The parameters of a training run, saved in checkpoints so that it can be resumed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TrainParams {
+    rules: Rules,
+    opening: Opening,
+    train_episodes: u32,
+    eval_episodes: u32,
+    cycles: u32,
+    opponent_epsilon: f32,
+    stats_file_name: String,
+    checkpoint: Option<CheckpointConfig>,
+    seed: u64,
+}
+
+/// Everything needed to continue a training run after `cycle` (the random state of the learning
+/// player is saved within it)
+#[derive(Serialize, Deserialize)]
+struct Checkpoint<P, F> {
+    params: TrainParams,
+    cycle: u32,
+    player: P,
+    adversary: F,
+    rng: SeededRng,
+    stats_file_len: u64,
+}
+
+/// A line of the stats file
+#[derive(Serialize)]
+struct StatsLine<T> {
+    cycle: u32,
+    seed: u64,
+    #[serde(flatten)]
+    stats: Option<T>,
+}
+
+/// Completed with the name of the state of the environment by `persist::kind_of()`
+const CHECKPOINT_KIND: &str = "training checkpoint";
+
+/// Train a given player against itself, in an environment of type `E` created with the given rules
+/// and opening.
+/// All randomness (of the player and its adversaries) is derived from `seed`, so the same seed
+/// produces the same results.
+/// If `checkpoint` is given, the progress is saved every few cycles and the run can be continued
+/// later with `resume()`.
+/// Fails if the stats file or a checkpoint cannot be written
+#[allow(clippy::too_many_arguments)]
 pub fn train<S, A, P, E>(
-    env: &mut E,
+    rules: Rules,
+    opening: Opening,
     player: &mut P,
     train_episodes: u32,
     eval_episodes: u32,
     cycles: u32,
     opponent_epsilon: f32,
     stats_file_name: &str,
-) where
-    S: State,
+    checkpoint: Option<CheckpointConfig>,
+    seed: u64,
+) -> io::Result<()>
+where
+    S: State + StateKind,
+    A: Action,
+    P: LearningPlayer<S, A> + Serialize,
+    P::Freezed: Serialize,
+    E: Environment<State = S, Action = A> + Variant,
+{
+    let params = TrainParams {
+        rules,
+        opening,
+        train_episodes,
+        eval_episodes,
+        cycles,
+        opponent_epsilon,
+        stats_file_name: stats_file_name.to_owned(),
+        checkpoint,
+        seed,
+    };
+    let mut rng = random::seeded(seed);
+    player.seed(rng.gen());
+    let stats_file = File::create(stats_file_name).map_err(in_file(stats_file_name))?;
+    let adversary = player.freezed();
+    let mut env = E::variant(rules, opening);
+    run_cycles(&mut env, player, adversary, rng, &params, 1, stats_file)
+}
+
+/// Continue a training run from the checkpoint saved in the given file and return the trained
+/// player. The environment is created with the rules and opening of the saved run.
+/// Stats written after the checkpoint was saved are discarded, so that the stats file ends up as if
+/// the run had never been interrupted.
+/// Fails if the checkpoint or the stats file cannot be read or written, or if the checkpoint was
+/// saved by another kind of player or environment
+pub fn resume<S, A, P, E>(checkpoint_file_name: &str) -> io::Result<P>
+where
+    S: State + StateKind,
     A: Action,
-    P: LearningPlayer<S, A>,
+    P: LearningPlayer<S, A> + Serialize + DeserializeOwned,
+    P::Freezed: Serialize + DeserializeOwned,
+    E: Environment<State = S, Action = A> + Variant,
+{
+    let checkpoint: Checkpoint<P, P::Freezed> = persist::load(
+        &persist::kind_of::<S>(CHECKPOINT_KIND),
+        checkpoint_file_name,
+    )?;
+    let Checkpoint {
+        params,
+        cycle,
+        mut player,
+        adversary,
+        rng,
+        stats_file_len,
+    } = checkpoint;
+    println!("Resuming {} after cycle {}", checkpoint_file_name, cycle);
+
+    let mut stats_file = OpenOptions::new()
+        .write(true)
+        .open(&params.stats_file_name)
+        .map_err(in_file(&params.stats_file_name))?;
+    stats_file.set_len(stats_file_len)?;
+    stats_file.seek(SeekFrom::End(0))?;
+    let mut env = E::variant(params.rules, params.opening);
+    run_cycles(
+        &mut env,
+        &mut player,
+        adversary,
+        rng,
+        &params,
+        cycle + 1,
+        stats_file,
+    )?;
+    Ok(player)
+}
+
+/// Add the name of the file to an IO error
+fn in_file(file_name: &str) -> impl Fn(io::Error) -> io::Error + '_ {
+    move |e| io::Error::new(e.kind(), format!("{}: {}", file_name, e))
+}
+
+fn run_cycles<S, A, P, E>(
+    env: &mut E,
+    player: &mut P,
+    adversary: P::Freezed,
+    mut rng: SeededRng,
+    params: &TrainParams,
+    first_cycle: u32,
+    mut stats_file: File,
+) -> io::Result<()>
+where
+    S: State + StateKind,
+    A: Action,
+    P: LearningPlayer<S, A> + Serialize,
+    P::Freezed: Serialize,
     E: Environment<State = S, Action = A>,
 {
-    let mut stats_file = File::create(stats_file_name).unwrap();
+    let cycles = params.cycles;
     let mut random_adversary = RandomPlayer::new();
-    let mut adversary = OpponentWrapper::new(player.freezed(), opponent_epsilon);
-    for cycle in 1..=cycles {
+    let mut adversary = OpponentWrapper::new(adversary, params.opponent_epsilon);
+    for cycle in first_cycle..=cycles {
+        // Seed the adversaries and the environment at the start of each cycle, so that only the
+        // main generator and the player need to be saved in checkpoints
+        adversary.seed(rng.gen());
+        Player::<S, A>::seed(&mut random_adversary, rng.gen());
+        env.seed(rng.gen());
+
         // Train against a fixed adversary
-        let train_score = run_duel(env, player, &mut adversary, train_episodes);
+        let train_score = run_duel(env, player, &mut adversary, params.train_episodes, None);
 
         // Eval the newly trained player against the fixed adversary
-        let mut new_adversary = OpponentWrapper::new(player.freezed(), opponent_epsilon);
+        let mut new_adversary = OpponentWrapper::new(player.freezed(), params.opponent_epsilon);
         let eval_score = run_duel(
             env,
             new_adversary.inner_mut(),
             adversary.inner_mut(),
-            eval_episodes,
+            params.eval_episodes,
+            None,
         );
 
         new_adversary.inner_mut().reset_stats();
@@ -39,11 +199,19 @@ pub fn train<S, A, P, E>(
             env,
             new_adversary.inner_mut(),
             &mut random_adversary,
-            eval_episodes,
+            params.eval_episodes,
+            None,
         );
         let eval_random_stats = new_adversary.inner_mut().stats();
-        serde_json::to_writer(&stats_file, &eval_random_stats).unwrap();
-        stats_file.write("\n".as_bytes()).unwrap();
+        let stats_line = StatsLine {
+            cycle,
+            seed: params.seed,
+            stats: eval_random_stats.as_ref(),
+        };
+        serde_json::to_writer(&stats_file, &stats_line)
+            .map_err(io::Error::from)
+            .and_then(|()| stats_file.write_all("\n".as_bytes()))
+            .map_err(in_file(&params.stats_file_name))?;
 
         adversary = new_adversary;
 
@@ -54,16 +222,56 @@ pub fn train<S, A, P, E>(
         println!("Eval random stats: {:?}", eval_random_stats);
 
         player.cycle_end();
+
+        if let Some(config) = &params.checkpoint {
+            if cycle % config.every_cycles == 0 || cycle == cycles {
+                let checkpoint = Checkpoint {
+                    params: params.clone(),
+                    cycle,
+                    player: &*player,
+                    adversary: adversary.inner(),
+                    rng: rng.clone(),
+                    stats_file_len: stats_file
+                        .stream_position()
+                        .map_err(in_file(&params.stats_file_name))?,
+                };
+                let kind = persist::kind_of::<S>(CHECKPOINT_KIND);
+                save_checkpoint(&checkpoint, &kind, &config.file_name)?;
+            }
+        }
     }
+    Ok(())
+}
+
+/// Write the checkpoint to a temporary file first, so that a crash while saving does not destroy
+/// the previous checkpoint
+fn save_checkpoint<P: Serialize, F: Serialize>(
+    checkpoint: &Checkpoint<&P, &F>,
+    kind: &str,
+    file_name: &str,
+) -> io::Result<()> {
+    let temp_file_name = format!("{}.tmp", file_name);
+    persist::save(
+        checkpoint,
+        kind,
+        &temp_file_name,
+        Format::from_path(file_name),
+    )
+    .map_err(in_file(&temp_file_name))?;
+    fs::rename(&temp_file_name, file_name).map_err(in_file(file_name))?;
+    println!("Saved checkpoint to {}", file_name);
+    Ok(())
 }
 
 /// Run multiple matches between two players, alternating which one starts the match
-/// Since we assume this is a zero-sum game, the score of the second one is simply the opposite
+/// Since we assume this is a zero-sum game, the score of the second one is simply the opposite.
+/// If `records` is given, the record of every match is appended to it
 pub fn run_duel<S, A, P1, P2, E>(
     env: &mut E,
     player_1: &mut P1,
     player_2: &mut P2,
     episodes: u32,
+    mut records: Option<&mut Vec<GameRecord<A>>>,
 ) -> f32
 where
     S: State,
@@ -75,15 +283,47 @@ where
     assert_eq!(episodes % 2, 0, "episodes must be even");
     let mut score = 0.;
     for _ in (0..episodes).step_by(2) {
-        score += run_match(env, player_1, player_2);
-        score -= run_match(env, player_2, player_1);
+        for &player_1_starts in &[true, false] {
+            let mut record = records.as_ref().map(|_| GameRecord::new());
+            if player_1_starts {
+                score += run_match(env, player_1, player_2, record.as_mut());
+            } else {
+                score -= run_match(env, player_2, player_1, record.as_mut());
+            }
+            if let (Some(records), Some(record)) = (records.as_deref_mut(), record) {
+                records.push(record);
+            }
+        }
     }
     score / episodes as f32
 }
 
+/// Apply the action, adding it to the record if there is one
+fn step<'a, S, A, E>(
+    env: &'a mut E,
+    action: A,
+    record: &mut Option<&mut GameRecord<A>>,
+) -> (S, f32, bool, &'a [A])
+where
+    S: State,
+    A: Action,
+    E: Environment<State = S, Action = A>,
+{
+    if let Some(record) = record.as_deref_mut() {
+        record.actions.push(action.clone());
+    }
+    env.step(action)
+}
+
 /// Run a match between two players and return the score the first one.
-/// Since we assume this is a zero-sum game, the score of the second one is simply the opposite
-pub fn run_match<S, A, P1, P2, E>(env: &mut E, player_1: &mut P1, player_2: &mut P2) -> f32
+/// Since we assume this is a zero-sum game, the score of the second one is simply the opposite.
+/// If `record` is given, the actions and the result of the match are written to it
+pub fn run_match<S, A, P1, P2, E>(
+    env: &mut E,
+    player_1: &mut P1,
+    player_2: &mut P2,
+    mut record: Option<&mut GameRecord<A>>,
+) -> f32
 where
     S: State,
     A: Action,
@@ -91,43 +331,124 @@ where
     P2: Player<S, A>,
     E: Environment<State = S, Action = A>,
 {
-    let (state, valid_actions) = env.reset();
-
-    // Player 1's first action
-    let action = player_1.start(state, valid_actions);
-    let (state, reward_1, done, valid_actions) = env.step(action);
-    let mut score = reward_1;
-    debug_assert!(!done);
-
-    // Player 2's first action
-    let action = player_2.start(state, valid_actions);
-    let (mut state, reward_2, done, mut valid_actions) = env.step(action);
-    score -= reward_2;
-    debug_assert!(!done);
+    let (mut state, mut valid_actions) = env.reset();
+    let mut player_1_turn = true;
+    let mut started = [false; 2];
+    // The rewards of each player since its last action
+    let mut rewards = [0.; 2];
+    let mut score = 0.;
 
     loop {
-        // Player 1 turn
-        let action = player_1.step(state, valid_actions, reward_1 - reward_2);
-        let (_state, reward_1, done, _valid_actions) = env.step(action);
-        state = _state;
-        valid_actions = _valid_actions;
-        score += reward_1;
+        let (me, other) = if player_1_turn { (0, 1) } else { (1, 0) };
+        let reward = std::mem::take(&mut rewards[me]);
+        let action = match (player_1_turn, started[me]) {
+            (true, false) => player_1.start(state, valid_actions),
+            (true, true) => player_1.step(state, valid_actions, reward),
+            (false, false) => player_2.start(state, valid_actions),
+            (false, true) => player_2.step(state, valid_actions, reward),
+        };
+        started[me] = true;
+
+        let (next_state, reward, done, next_actions) = step(env, action, &mut record);
+        rewards[me] += reward;
+        rewards[other] -= reward;
+        score += if player_1_turn { reward } else { -reward };
         if done {
-            player_1.end(state.clone(), reward_1);
-            player_2.end(state, reward_2 - reward_1);
-            return score;
+            // The player that ended the game is told first
+            if player_1_turn {
+                player_1.end(next_state.clone(), rewards[0]);
+                player_2.end(next_state, rewards[1]);
+            } else {
+                player_2.end(next_state.clone(), rewards[1]);
+                player_1.end(next_state, rewards[0]);
+            }
+            break;
         }
 
-        // Player 2 turn
-        let action = player_2.step(state, valid_actions, reward_2 - reward_1);
-        let (_state, reward_2, done, _valid_actions) = env.step(action);
-        state = _state;
-        valid_actions = _valid_actions;
-        score -= reward_2;
-        if done {
-            player_2.end(state.clone(), reward_2);
-            player_1.end(state, reward_1 - reward_2);
-            return score;
+        // Some environments split a turn into several actions
+        if !next_state.continues_turn() {
+            player_1_turn = !player_1_turn;
+        }
+        state = next_state;
+        valid_actions = next_actions;
+    }
+
+    if let Some(record) = record {
+        record.result = Some(GameResult::from_score(score));
+    }
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::{self, Environment};
+    use crate::player::{QLearnedPlayer, QLearningPlayer};
+
+    type Learner = QLearningPlayer<environment::State>;
+
+    fn temp_file_name(name: &str) -> String {
+        let name = format!("quarto-rs-{}-{}", std::process::id(), name);
+        std::env::temp_dir().join(name).to_str().unwrap().to_owned()
+    }
+
+    fn train_for(cycles: u32, stats_file_name: &str, checkpoint_file_name: &str) -> Learner {
+        let mut player = Learner::default();
+        let checkpoint = CheckpointConfig {
+            every_cycles: 2,
+            file_name: checkpoint_file_name.to_owned(),
+        };
+        train::<_, _, _, Environment>(
+            Rules::standard(),
+            Opening::Random,
+            &mut player,
+            20,
+            10,
+            cycles,
+            0.1,
+            stats_file_name,
+            Some(checkpoint),
+            42,
+        )
+        .unwrap();
+        player
+    }
+
+    #[test]
+    fn resumed_runs_match_uninterrupted_runs() {
+        let stats = [temp_file_name("stats-1"), temp_file_name("stats-2")];
+        let checkpoints = [
+            temp_file_name("checkpoint-1.json"),
+            temp_file_name("checkpoint-2.json"),
+        ];
+        let player = train_for(4, &stats[0], &checkpoints[0]);
+
+        // Interrupt a run of 4 cycles after its checkpoint of cycle 2, with some stats written
+        // after it
+        train_for(2, &stats[1], &checkpoints[1]);
+        let kind = persist::kind_of::<environment::State>(CHECKPOINT_KIND);
+        let mut checkpoint: Checkpoint<Learner, QLearnedPlayer<environment::State>> =
+            persist::load(&kind, &checkpoints[1]).unwrap();
+        checkpoint.params.cycles = 4;
+        checkpoint.params.stats_file_name = stats[1].clone();
+        persist::save(&checkpoint, &kind, &checkpoints[1], Format::Json).unwrap();
+        let mut stats_file = OpenOptions::new().append(true).open(&stats[1]).unwrap();
+        stats_file.write_all(b"{\"cycle\":3}\n").unwrap();
+
+        let resumed: Learner = resume::<_, _, _, Environment>(&checkpoints[1]).unwrap();
+        // Compare values, since the order of maps in the stats depends on their hashing
+        let read = |name: &String| -> Vec<serde_json::Value> {
+            let stats = fs::read_to_string(name).unwrap();
+            let lines = stats
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap());
+            lines.collect()
+        };
+        assert_eq!(read(&stats[0]), read(&stats[1]));
+        let to_value = |player: &Learner| serde_json::to_value(player.stats()).unwrap();
+        assert_eq!(to_value(&player), to_value(&resumed));
+        for name in stats.iter().chain(&checkpoints) {
+            fs::remove_file(name).unwrap();
         }
     }
 }
@@ -1,24 +1,69 @@
-use serde::Serialize;
+//! Define traits for environment, action, players, etc
 
-///! Define traits for environment, action, players, etc
+use serde::Serialize;
 
 /// The state of the environment
 pub trait State: Clone + std::hash::Hash + Eq {
     fn game_depth(&self) -> u16;
+
+    /// Whether the player that took the action leading to this state also takes the next one.
+    /// By default, players alternate after every action
+    fn continues_turn(&self) -> bool {
+        false
+    }
+}
+
+/// An action that can be applied to an environment.
+/// Actions are ordered so that players can list them in a stable order regardless of how the
+/// environment generated them
+pub trait Action: Clone + Ord {}
+
+/// A bijection between the actions of a state and the actions of a symmetric state
+pub trait Transform<A: Action>: Copy {
+    fn apply(&self, action: &A) -> A;
+
+    fn inverse(&self) -> Self;
 }
 
-/// An action that can be applied to an environment
-pub trait Action: Clone {}
+/// A state that is equivalent to others by symmetry (rotations, reflections, etc).
+/// All states in the same class share the same canonical representative, so players can learn
+/// a single value for all of them
+pub trait Canonical<A: Action>: State {
+    type Transform: Transform<A>;
+
+    /// Return the canonical representative of this state and the transform that maps the
+    /// actions of this state into actions of the canonical one
+    fn canonical(&self) -> (Self, Self::Transform);
+}
 
 /// An environment, that can be represented as a state and to which actions can be applied.
 /// The environment defines the associated types of the state and action.
+/// The valid actions are borrowed from the environment, so that it can reuse the same memory for
+/// the whole game instead of allocating them on every step
 pub trait Environment {
     type State: State;
     type Action: Action;
 
-    fn reset(&mut self) -> (Self::State, Vec<Self::Action>);
+    fn reset(&mut self) -> (Self::State, &[Self::Action]);
+
+    fn step(&mut self, action: Self::Action) -> (Self::State, f32, bool, &[Self::Action]);
+
+    /// Reset the random number generator of the environment, if it has one
+    fn seed(&mut self, _seed: u64) {}
+}
+
+/// An environment that can take back the actions applied to it, so that search algorithms can
+/// explore the game without cloning the environment at every node
+pub trait Reversible: Environment {
+    /// Restore the environment as it was before the last action and return that action, or `None`
+    /// if there is no action to undo
+    fn undo(&mut self) -> Option<Self::Action>;
+}
 
-    fn step(&mut self, action: Self::Action) -> (Self::State, f32, bool, Vec<Self::Action>);
+/// An environment that can be recreated from any of its states, so that players can simulate
+/// games from the state they are given
+pub trait Simulator: Environment + Clone {
+    fn from_state(state: &Self::State) -> Self;
 }
 
 /// A player that can take actions from a given state.
@@ -26,18 +71,21 @@ pub trait Environment {
 pub trait Player<S: State, A: Action> {
     type Stats: std::fmt::Debug + Serialize;
 
-    fn take_action(&mut self, state: S, actions: Vec<A>) -> A;
+    fn take_action(&mut self, state: S, actions: &[A]) -> A;
 
-    fn start(&mut self, state: S, actions: Vec<A>) -> A {
+    fn start(&mut self, state: S, actions: &[A]) -> A {
         self.take_action(state, actions)
     }
 
-    fn step(&mut self, state: S, actions: Vec<A>, _reward: f32) -> A {
+    fn step(&mut self, state: S, actions: &[A], _reward: f32) -> A {
         self.take_action(state, actions)
     }
 
     fn end(&mut self, _state: S, _reward: f32) {}
 
+    /// Reset the random number generators of the player, so that its decisions can be reproduced
+    fn seed(&mut self, _seed: u64) {}
+
     fn reset_stats(&mut self) {}
 
     fn stats(&self) -> Option<Self::Stats> {